@@ -0,0 +1,32 @@
+//! Native throughput of the mining hot loop: `cargo bench -p windoge_miner`.
+//!
+//! Instruction counts only exist inside a canister, where `mining_step`
+//! records them as `instructions_per_hash` in `get_state`. Native hashes per
+//! second are the number to compare between changes to `hash_nonce`.
+
+use std::hint::black_box;
+use std::time::Instant;
+use windoge_miner::{ hash_nonce, HEADER_LEN };
+
+const HASHES: u64 = 20_000_000;
+
+fn main() {
+    let mut buf = [0x5a_u8; HEADER_LEN];
+    let mut best = 0;
+
+    let start = Instant::now();
+    for nonce in 0..HASHES {
+        let hash = hash_nonce(black_box(&mut buf), nonce as u128);
+        best = std::cmp::max(best, hash.leading_zeros());
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} hashes in {:?}: {:.0} hashes/s, {:.2} ns/hash, best {} leading zeros",
+        HASHES,
+        elapsed,
+        (HASHES as f64) / elapsed.as_secs_f64(),
+        (elapsed.as_nanos() as f64) / (HASHES as f64),
+        best
+    );
+}
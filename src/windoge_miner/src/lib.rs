@@ -1,11 +1,34 @@
 use candid::{ CandidType, Principal };
-use ic_cdk::update;
 use rapidhash::RapidHasher;
 use std::{ cell::RefCell, hash::Hasher };
 use serde::{ Deserialize, Serialize };
 
 const LEDGER_ID: &str = "hx36f-waaaa-aaaai-aq32q-cai";
-const CHUNK_SIZE: u64 = 1000000; // 1M hashes
+/// Hashes between two instruction counter checks.
+const BATCH_SIZE: u64 = 10_000;
+/// Instructions a `mining_step` message may use, below the 40B update limit.
+const INSTRUCTION_BUDGET: u64 = 30_000_000_000;
+/// version, prev_hash, merkle_root and timestamp precede the nonce.
+const NONCE_OFFSET: usize = 44;
+pub const HEADER_LEN: usize = NONCE_OFFSET + 16;
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+/// How long a pushed block, or the backend refusing templates, keeps the miner
+/// from polling; past it one poll checks whether the backend switched to pull mode.
+const PUSH_MODE_TTL: u64 = 30 * 60 * 1_000_000_000;
+/// The backend's answer to template requests in push mode.
+const PUSH_MODE_ERROR: &str = "blocks are pushed to miners, templates are not served";
+const RESUME_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Lowest reserve floor an owner may set.
+pub const MIN_CYCLES: u64 = 100_000_000_000;
+/// Kept back on decommission for the freezing threshold and the deposit call.
+pub const DECOMMISSION_RESERVE: u64 = 10_000_000_000;
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Shares a mining step should find on average, see `step_share_difficulty`.
+const SHARES_PER_STEP: u64 = 4;
+/// Most shares the backend accepts in one `submit_shares` call.
+const MAX_SHARES_PER_SUBMIT: usize = 32;
+
+pub mod memory;
 
 type Hash = u128;
 
@@ -35,76 +58,372 @@ pub struct BlockHeader {
     difficulty: u32,
 }
 
-#[update(hidden = true)]
-pub async fn find_solution() {
-    if
-        ic_cdk::caller() != ic_cdk::api::id() &&
-        ic_cdk::caller() != Principal::from_text(LEDGER_ID).unwrap()
-    {
-        ic_cdk::println!("Caller is not authorized!");
+/// Nonces `miner_id << 64..(miner_id + 1) << 64` belong to this miner alone;
+/// the backend hands every miner a distinct id (its extranonce).
+pub fn nonce_range(miner_id: u32) -> (u128, u128) {
+    let start = (miner_id as u128) << 64;
+    (start, start + (1 << 64))
+}
+
+/// The part of the backend's `BlockTemplate` the miner uses.
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+struct BlockTemplate {
+    template_id: u64,
+    block: Block,
+    miner_id: u32,
+    share_difficulty: u32,
+}
+
+/// Starts mining `block`, or swaps it in if it is a refreshed template of the
+/// block already being mined.
+pub fn load_block(
+    block: Block,
+    miner_id: u32,
+    share_difficulty: Option<u32>,
+    template_id: Option<u64>
+) {
+    // Keep the solve time and cycle accounting of the height being mined.
+    let is_refresh = read_state(|s|
+        s.current_block.as_ref().is_some_and(|b| b.header.height == block.header.height)
+    );
+    if is_refresh {
+        mutate_state(|s| {
+            s.current_block = Some(block.clone());
+            s.template_id = template_id;
+        });
+        ic_cdk::println!("Template refreshed: {:?}", template_id);
         return;
     }
 
-    let seed = ic_cdk::api::time();
-    let miner_id = read_state(|s| s.miner_id);
-    let mut block = read_state(|s| s.current_block.clone().unwrap());
+    mutate_state(|s| {
+        s.current_block = Some(block.clone());
+        s.last_mining_timestamp = ic_cdk::api::time();
+        s.mining_start_cycles = ic_cdk::api::canister_balance();
+        s.mining_start_time = ic_cdk::api::time();
+        s.mining_temp_time = ic_cdk::api::time();
+        s.mining_temp_cycles = ic_cdk::api::canister_balance();
+        s.miner_id = miner_id;
+        s.next_nonce = nonce_range(miner_id).0;
+        s.share_difficulty = share_difficulty;
+        s.template_id = template_id;
+        s.mining_cycle = 0;
+    });
 
-    ic_cdk::println!("Mining...");
+    ic_cdk::println!("New block received: {:?}", block.header.height);
 
-    for i in 0..CHUNK_SIZE {
-        block.nonce = xorshift_random(seed as u128, i as u128, miner_id as u128);
-
-        let hash = calculate_hash(&block);
-
-        if hash.leading_zeros() >= block.header.difficulty {
-            if let Err(err) = submit_solution(block.clone()).await {
-                ic_cdk::println!("Error submitting solution: {:?}", err);
-            } else {
-                ic_cdk::println!("Solution submitted successfully!");
-                update_mining_stats(false);
-                mutate_state(|s| {
-                    s.blocks_mined += 1;
-                });
-                return;
-            }
+    // A running loop picks the block up at its next step; a stopped miner keeps it for later.
+    // A new block resets the per-block limit, the first step rechecks the others.
+    let status = read_state(|s| s.status);
+    if matches!(status, MiningStatus::Idle | MiningStatus::OutOfCycles | MiningStatus::OverBudget) {
+        start_mining();
+    }
+}
+
+/// The status mining must stop with under the spending policy, if any.
+pub fn spending_stop() -> Option<MiningStatus> {
+    let balance = ic_cdk::api::canister_balance();
+    read_state(|s| {
+        if balance < s.spending_policy.reserve_floor {
+            return Some(MiningStatus::OutOfCycles);
+        }
+        let over_total = s.cycle_budget.is_some_and(|budget| s.cycles_burned >= budget);
+        let over_block = s.spending_policy.max_cycles_per_block.is_some_and(
+            |max| s.mining_start_cycles.saturating_sub(balance) >= max
+        );
+        let over_day = s.spending_policy.max_cycles_per_day.is_some_and(
+            |max| s.cycles_burned_today >= max
+        );
+        if over_total || over_block || over_day {
+            return Some(MiningStatus::OverBudget);
         }
+        None
+    })
+}
+
+/// Restarts a miner stopped by its spending policy once the policy allows again,
+/// e.g. after a top-up, a new day or a policy change. A miner whose block was
+/// dropped goes idle instead, which tells the backend to push it blocks again.
+pub fn resume_if_allowed() {
+    let (stopped, has_block) = read_state(|s| (
+        matches!(s.status, MiningStatus::OutOfCycles | MiningStatus::OverBudget),
+        s.current_block.is_some(),
+    ));
+    if !stopped {
+        return;
+    }
+    update_mining_stats();
+    if spending_stop().is_some() {
+        return;
     }
+    if has_block {
+        start_mining();
+    } else {
+        set_status(MiningStatus::Idle);
+    }
+}
 
-    let should_update_stats = mutate_state(|s| {
-        s.mining_cycle += 1;
-        s.mining_cycle % 5 == 0
+/// Cycles that leave the canister without being burned, e.g. withdrawals,
+/// so they don't count against the spending limits. Recorded before the call
+/// that sends them, mining steps keep running while it is in flight.
+pub fn record_cycles_out(amount: u64) {
+    mutate_state(|s| {
+        s.mining_start_cycles = s.mining_start_cycles.saturating_sub(amount);
+        s.mining_temp_cycles = s.mining_temp_cycles.saturating_sub(amount);
     });
+}
 
-    if should_update_stats {
-        update_mining_stats(true);
-    }
-
-    ic_cdk::spawn(async {
-        match ic_cdk::api::call::call_raw(
-            ic_cdk::api::id(),
-            "find_solution",
-            candid::encode_args(()).unwrap(),
-            0
-        ).await {
-            Ok(_) => (),
-            Err(err) => {
-                ic_cdk::println!("Error calling self: {:?}", err);
-                update_mining_stats(false);
+/// Undoes `record_cycles_out` when the call failed and the cycles came back.
+pub fn record_cycles_returned(amount: u64) {
+    mutate_state(|s| {
+        s.mining_start_cycles += amount;
+        s.mining_temp_cycles += amount;
+    });
+}
+
+/// Starts the mining loop unless it is already running.
+pub fn start_mining() {
+    if matches!(read_state(|s| s.status), MiningStatus::Mining | MiningStatus::Submitting) {
+        return;
+    }
+    ic_cdk::println!("Starting mining...");
+    set_status(MiningStatus::Mining);
+    schedule_step();
+}
+
+/// Stops the loop after the current step; only `start_mining` restarts it.
+pub fn stop_mining() {
+    set_status(MiningStatus::Paused);
+    update_mining_stats();
+}
+
+pub fn set_status(status: MiningStatus) {
+    let previous = mutate_state(|s| {
+        let previous = s.status;
+        s.status = status;
+        s.is_mining = matches!(status, MiningStatus::Mining | MiningStatus::Submitting);
+        previous
+    });
+
+    // The backend stops pushing blocks to miners that cannot spend, and starts again on resume.
+    let spending_stopped = |status|
+        matches!(status, MiningStatus::OutOfCycles | MiningStatus::OverBudget);
+    if previous != status && (spending_stopped(status) || spending_stopped(previous)) {
+        let balance = ic_cdk::api::canister_balance();
+        ic_cdk::spawn(async move {
+            if let Err(e) = notify_backend("notify_miner_status", (status, balance)).await {
+                ic_cdk::println!("Error reporting status: {}", e);
             }
+        });
+    }
+}
+
+/// Runs the next mining step in its own message, at most one is pending at a time.
+fn schedule_step() {
+    NEXT_STEP.with(|next| {
+        let mut next = next.borrow_mut();
+        if next.is_none() {
+            *next = Some(
+                ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+                    NEXT_STEP.with(|next| next.borrow_mut().take());
+                    ic_cdk::spawn(mining_step());
+                })
+            );
         }
+    });
+}
 
+/// Polls the backend for work in pull mode. In push mode blocks arrive through
+/// `push_block` and the poll is skipped.
+pub fn start_template_polling() {
+    ic_cdk_timers::set_timer_interval(POLL_INTERVAL, || {
+        if !in_push_mode() {
+            ic_cdk::spawn(poll_template());
+        }
     });
 }
 
-fn update_mining_stats(is_mining: bool) {
+/// Lifts daily spending stops, which no backend call does.
+pub fn start_resume_checks() {
+    ic_cdk_timers::set_timer_interval(RESUME_CHECK_INTERVAL, resume_if_allowed);
+}
+
+/// Called when the backend pushes a block or refuses a template.
+pub fn mark_push_mode() {
+    PUSH_MODE_SEEN_AT.with(|at| {
+        *at.borrow_mut() = Some(ic_cdk::api::time());
+    });
+}
+
+fn in_push_mode() -> bool {
+    let now = ic_cdk::api::time();
+    PUSH_MODE_SEEN_AT.with(|at| {
+        at.borrow().is_some_and(|at| now < at.saturating_add(PUSH_MODE_TTL))
+    })
+}
+
+async fn poll_template() {
+    let ledger_id = read_state(|s| s.ledger_id);
+    let res: Result<(Result<BlockTemplate, String>,), _> = ic_cdk::api::call::call(
+        ledger_id,
+        "get_block_template",
+        (ic_cdk::api::id(),)
+    ).await;
+
+    let template = match res {
+        Ok((Ok(template),)) => template,
+        Ok((Err(e),)) if e == PUSH_MODE_ERROR => {
+            mark_push_mode();
+            return;
+        }
+        _ => {
+            return;
+        }
+    };
+    let is_current = read_state(|s|
+        s.current_block.is_some() && s.template_id == Some(template.template_id)
+    );
+    if !is_current {
+        load_block(
+            template.block,
+            template.miner_id,
+            Some(template.share_difficulty),
+            Some(template.template_id)
+        );
+    }
+}
+
+/// Hashes one message's worth of nonces, then schedules the next step.
+async fn mining_step() {
+    if read_state(|s| s.status) != MiningStatus::Mining {
+        return;
+    }
+
+    update_mining_stats();
+    if let Some(status) = spending_stop() {
+        ic_cdk::println!("Spending limit reached, stopping: {:?}", status);
+        set_status(status);
+        return;
+    }
+
+    let current = read_state(|s| s.current_block.clone().map(|block| (block, s.template_id)));
+    let (mut block, template_id) = match current {
+        Some(current) => current,
+        None => {
+            ic_cdk::println!("No block to mine, stopping");
+            set_status(MiningStatus::Idle);
+            update_mining_stats();
+            return;
+        }
+    };
+
+    let share_difficulty = read_state(|s|
+        s.share_difficulty.map(|base| step_share_difficulty(base, s.chunk_size))
+    );
+    let mut shares: Vec<u128> = vec![];
+    let mut buf = header_bytes(&block);
+    let mut hashed: u64 = 0;
+    let mut batch_cost: u64 = 0;
+
+    ic_cdk::println!("Mining...");
+
+    // Hash in batches until the next one could exceed the message's instruction budget.
+    while ic_cdk::api::performance_counter(0).saturating_add(batch_cost) < INSTRUCTION_BUDGET {
+        let batch_start = ic_cdk::api::performance_counter(0);
+        let first_nonce = claim_nonces(BATCH_SIZE);
+
+        for i in 0..BATCH_SIZE {
+            let nonce = first_nonce + (i as u128);
+            let hash = hash_nonce(&mut buf, nonce);
+
+            if let Some(difficulty) = share_difficulty {
+                if shares.len() < MAX_SHARES_PER_SUBMIT && hash.leading_zeros() >= difficulty {
+                    shares.push(nonce);
+                }
+            }
+
+            if hash.leading_zeros() >= block.header.difficulty {
+                block.nonce = nonce;
+                set_status(MiningStatus::Submitting);
+                let result = submit_solution(block.clone(), template_id).await;
+                // Stopped, paused or restarted while the call was in flight.
+                if read_state(|s| s.status) != MiningStatus::Submitting {
+                    return;
+                }
+                match result {
+                    Ok(_) => {
+                        ic_cdk::println!("Solution submitted successfully!");
+                        set_status(MiningStatus::Idle);
+                        update_mining_stats();
+                        mutate_state(|s| {
+                            s.blocks_mined += 1;
+                        });
+                        return;
+                    }
+                    Err(err) => {
+                        ic_cdk::println!("Error submitting solution: {:?}", err);
+                        set_status(MiningStatus::Mining);
+                    }
+                }
+            }
+        }
+
+        hashed += BATCH_SIZE;
+        // The counter restarts after an await, such a batch is not measured.
+        let batch_end = ic_cdk::api::performance_counter(0);
+        if batch_end > batch_start {
+            batch_cost = std::cmp::max(batch_cost, batch_end - batch_start);
+        }
+    }
+
+    mutate_state(|s| {
+        s.chunk_size = hashed;
+        s.instructions_per_hash = batch_cost / BATCH_SIZE;
+    });
+
+    // One call per step keeps the backend call rate bounded.
+    if let Some(difficulty) = share_difficulty.filter(|_| !shares.is_empty()) {
+        submit_shares(block.header.height, shares, difficulty, template_id);
+    }
+
+    mutate_state(|s| {
+        s.mining_cycle += 1;
+    });
+
+    schedule_step();
+}
+
+/// Calls a backend notification endpoint, all of which answer `Result<(), String>`.
+pub async fn notify_backend<T: candid::utils::ArgumentEncoder>(
+    method: &str,
+    args: T
+) -> Result<(), String> {
+    let ledger_id = read_state(|s| s.ledger_id);
+    let res: Result<(Result<(), String>,), _> = ic_cdk::api::call::call(
+        ledger_id,
+        method,
+        args
+    ).await;
+    match res {
+        Ok((res,)) => res,
+        Err((code, msg)) =>
+            Err(format!("Error while calling minter canister ({:?}): {:?}", code, msg)),
+    }
+}
+
+fn update_mining_stats() {
     let current_balance = ic_cdk::api::canister_balance();
+    let day = ic_cdk::api::time() / DAY_NANOS;
     mutate_state(|s| {
+        if s.spending_day != day {
+            s.spending_day = day;
+            s.cycles_burned_today = 0;
+        }
         if current_balance < s.mining_temp_cycles {
             s.cycles_burned += s.mining_temp_cycles - current_balance;
+            s.cycles_burned_today += s.mining_temp_cycles - current_balance;
         }
         s.mining_temp_cycles = current_balance;
         s.time_spent_mining += ic_cdk::api::time() - s.mining_temp_time;
-        s.is_mining = is_mining;
         s.last_mining_timestamp = ic_cdk::api::time();
         s.mining_temp_time = ic_cdk::api::time();
     });
@@ -118,19 +437,19 @@ struct Stats {
     miner: Principal,
 }
 
-async fn submit_solution(block: Block) -> Result<(), String> {
+async fn submit_solution(block: Block, template_id: Option<u64>) -> Result<(), String> {
     let ledger_id = read_state(|s| s.ledger_id);
     let start_time = read_state(|s| s.mining_start_time);
     let start_cycles = read_state(|s| s.mining_start_cycles);
 
     let stats = Stats {
-        cycles_burned: start_cycles - ic_cdk::api::canister_balance(),
+        cycles_burned: start_cycles.saturating_sub(ic_cdk::api::canister_balance()),
         timestamp: ic_cdk::api::time(),
         solve_time: ic_cdk::api::time() - start_time,
         miner: ic_cdk::api::id(),
     };
     let res_gov: Result<(Result<bool, String>,), (i32, String)> = ic_cdk::api::call
-        ::call(ledger_id, "submit_solution", (block, stats)).await
+        ::call(ledger_id, "submit_solution", (block, stats, template_id)).await
         .map_err(|(code, msg)| (code as i32, msg));
     match res_gov {
         Ok((res,)) =>
@@ -143,17 +462,68 @@ async fn submit_solution(block: Block) -> Result<(), String> {
     }
 }
 
-fn calculate_hash(block: &Block) -> Hash {
-    let mut hasher = RapidHasher::new(0);
-    let mut data = Vec::new();
+fn submit_shares(
+    block_height: u64,
+    nonces: Vec<u128>,
+    difficulty: u32,
+    template_id: Option<u64>
+) {
+    let ledger_id = read_state(|s| s.ledger_id);
+    ic_cdk::spawn(async move {
+        let res: Result<(Result<u64, String>,), _> = ic_cdk::api::call::call(
+            ledger_id,
+            "submit_shares",
+            (block_height, nonces, difficulty, template_id)
+        ).await;
+        match res {
+            Ok((Ok(_),)) => (),
+            Ok((Err(e),)) => ic_cdk::println!("Share rejected: {}", e),
+            Err(err) => ic_cdk::println!("Error submitting share: {:?}", err),
+        }
+    });
+}
 
-    data.extend_from_slice(&block.header.version.to_le_bytes());
-    data.extend_from_slice(&block.header.prev_hash.to_le_bytes());
-    data.extend_from_slice(&block.header.merkle_root.to_le_bytes());
-    data.extend_from_slice(&block.header.timestamp.to_le_bytes());
-    data.extend_from_slice(&block.nonce.to_le_bytes());
+/// Share difficulty for a step hashing about `chunk_size` nonces: the backend's
+/// target, raised so the step finds around `SHARES_PER_STEP` shares. Every
+/// share is credited `2^difficulty` hashes, so raising it keeps the work exact
+/// while the shares still fit in one call.
+fn step_share_difficulty(base: u32, chunk_size: u64) -> u32 {
+    let per_share = chunk_size / SHARES_PER_STEP;
+    if per_share == 0 {
+        return base;
+    }
+    std::cmp::max(base, 63 - per_share.leading_zeros())
+}
 
-    hasher.write(&data);
+/// Reserves the next `count` nonces of this miner's range, wrapping around at its end.
+fn claim_nonces(count: u64) -> u128 {
+    mutate_state(|s| {
+        let (start, end) = nonce_range(s.miner_id);
+        if s.next_nonce < start || s.next_nonce + (count as u128) > end {
+            s.next_nonce = start;
+        }
+        let first_nonce = s.next_nonce;
+        s.next_nonce += count as u128;
+        first_nonce
+    })
+}
+
+/// Header bytes as hashed by the backend, with the nonce left to `hash_nonce`.
+fn header_bytes(block: &Block) -> [u8; HEADER_LEN] {
+    let mut buf = [0_u8; HEADER_LEN];
+    buf[0..4].copy_from_slice(&block.header.version.to_le_bytes());
+    buf[4..20].copy_from_slice(&block.header.prev_hash.to_le_bytes());
+    buf[20..36].copy_from_slice(&block.header.merkle_root.to_le_bytes());
+    buf[36..NONCE_OFFSET].copy_from_slice(&block.header.timestamp.to_le_bytes());
+    buf
+}
+
+/// Proof-of-work hash of the header in `buf` with `nonce`; only the nonce bytes are rewritten.
+pub fn hash_nonce(buf: &mut [u8; HEADER_LEN], nonce: u128) -> Hash {
+    buf[NONCE_OFFSET..].copy_from_slice(&nonce.to_le_bytes());
+
+    let mut hasher = RapidHasher::new(0);
+    hasher.write(buf);
     let hash64 = hasher.finish();
 
     let hash128_high = {
@@ -165,30 +535,49 @@ fn calculate_hash(block: &Block) -> Hash {
     ((hash128_high as u128) << 64) | (hash64 as u128)
 }
 
-fn xorshift_random(mut seed1: u128, mut seed2: u128, mut seed3: u128) -> u128 {
-    seed1 ^= seed1 << 13;
-    seed1 ^= seed1 >> 17;
-    seed1 ^= seed1 << 5;
-
-    seed2 ^= seed2 << 7;
-    seed2 ^= seed2 >> 11;
-    seed2 ^= seed2 << 3;
-
-    seed3 ^= seed3 << 9;
-    seed3 ^= seed3 >> 13;
-    seed3 ^= seed3 << 7;
+thread_local! {
+    static __STATE: RefCell<Option<MinerState>> = RefCell::default();
+    static NEXT_STEP: RefCell<Option<ic_cdk_timers::TimerId>> = RefCell::default();
+    /// Not persisted: after an upgrade one poll, or the next push, finds the mode again.
+    static PUSH_MODE_SEEN_AT: RefCell<Option<u64>> = RefCell::default();
+}
 
-    let mix1 = seed1.rotate_left(32);
-    let mix2 = seed2.rotate_right(29);
-    let mix3 = seed3.rotate_left(37);
+#[derive(Debug, Clone, Copy, Default, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MiningStatus {
+    /// Waiting for a block.
+    #[default]
+    Idle,
+    Mining,
+    /// A solution is being submitted to the backend.
+    Submitting,
+    /// Stopped by the owner.
+    Paused,
+    /// The balance reached the reserve floor, stopped until the miner is topped up.
+    OutOfCycles,
+    /// A spending limit was reached, mining resumes once it allows again.
+    OverBudget,
+}
 
-    mix1.wrapping_add(mix2).wrapping_add(mix3)
+/// Owner-set limits on what mining may spend. The reserve floor keeps the
+/// canister well above its freezing threshold.
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SpendingPolicy {
+    pub max_cycles_per_block: Option<u64>,
+    pub max_cycles_per_day: Option<u64>,
+    pub reserve_floor: u64,
 }
-thread_local! {
-    static __STATE: RefCell<Option<MinerState>> = RefCell::default();
+
+impl Default for SpendingPolicy {
+    fn default() -> Self {
+        Self {
+            max_cycles_per_block: None,
+            max_cycles_per_day: None,
+            reserve_floor: MIN_CYCLES,
+        }
+    }
 }
 
-#[derive(Clone, CandidType)]
+#[derive(Clone, CandidType, Serialize, Deserialize)]
 pub struct MinerState {
     pub ledger_id: Principal,
     pub owner: Principal,
@@ -196,6 +585,8 @@ pub struct MinerState {
     pub blocks_mined: u64,
     pub last_mining_timestamp: u64,
     pub is_mining: bool,
+    #[serde(default)]
+    pub status: MiningStatus,
     pub time_spent_mining: u64,
     pub mining_start_time: u64,
     pub mining_start_cycles: u64,
@@ -203,7 +594,34 @@ pub struct MinerState {
     pub mining_temp_cycles: u64,
     pub current_block: Option<Block>,
     pub miner_id: u32,
+    /// First nonce of the next chunk to search, within `nonce_range(miner_id)`.
+    #[serde(default)]
+    pub next_nonce: u128,
     pub mining_cycle: u64,
+    /// Total cycles mining may burn before the miner stops, set by the owner.
+    #[serde(default)]
+    pub cycle_budget: Option<u64>,
+    #[serde(default)]
+    pub spending_policy: SpendingPolicy,
+    /// Day number (`time / 24h`) `cycles_burned_today` counts for.
+    #[serde(default)]
+    pub spending_day: u64,
+    #[serde(default)]
+    pub cycles_burned_today: u64,
+    /// Account the backend credits rewards to instead of the owner.
+    #[serde(default)]
+    pub reward_account: Option<Principal>,
+    /// Hashes computed by the last mining step.
+    #[serde(default)]
+    pub chunk_size: u64,
+    #[serde(default)]
+    pub instructions_per_hash: u64,
+    /// Share target set by the backend, the floor of `step_share_difficulty`.
+    #[serde(default)]
+    pub share_difficulty: Option<u32>,
+    /// Template id of `current_block`, sent back with solutions and shares.
+    #[serde(default)]
+    pub template_id: Option<u64>,
 }
 
 impl MinerState {
@@ -214,6 +632,7 @@ impl MinerState {
             owner,
             cycles_burned: 0,
             is_mining: false,
+            status: MiningStatus::Idle,
             last_mining_timestamp: 0,
             time_spent_mining: 0,
             mining_start_time: 0,
@@ -222,7 +641,17 @@ impl MinerState {
             mining_temp_cycles: 0,
             current_block: None,
             miner_id: 0,
+            next_nonce: 0,
             mining_cycle: 0,
+            cycle_budget: None,
+            spending_policy: SpendingPolicy::default(),
+            spending_day: 0,
+            cycles_burned_today: 0,
+            reward_account: None,
+            chunk_size: 0,
+            instructions_per_hash: 0,
+            share_difficulty: None,
+            template_id: None,
         }
     }
 }
@@ -240,3 +669,53 @@ pub fn replace_state(state: MinerState) {
         *s.borrow_mut() = Some(state);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Must match the backend's `nonce_range`, the backend tests that extranonces are unique.
+    #[test]
+    fn nonce_ranges_are_disjoint() {
+        for id in [0, 1, 2, u32::MAX - 1, u32::MAX] {
+            let (start, end) = nonce_range(id);
+            assert_eq!(start, (id as u128) << 64);
+            assert_eq!(end - start, 1 << 64);
+        }
+        assert_eq!(nonce_range(0).1, nonce_range(1).0);
+        assert_eq!(nonce_range(u32::MAX - 1).1, nonce_range(u32::MAX).0);
+        assert_eq!(nonce_range(u32::MAX).1, 1 << 96);
+    }
+
+    #[test]
+    fn step_share_difficulty_targets_a_few_shares() {
+        // Nothing measured yet: the backend's target.
+        assert_eq!(step_share_difficulty(16, 0), 16);
+        // Small steps never go below the target.
+        assert_eq!(step_share_difficulty(16, 10_000), 16);
+        for chunk_size in [1_u64 << 20, 3_000_000, 1 << 30] {
+            let difficulty = step_share_difficulty(16, chunk_size);
+            let expected_shares = chunk_size >> difficulty;
+            assert!((SHARES_PER_STEP..2 * SHARES_PER_STEP).contains(&expected_shares));
+        }
+    }
+
+    #[test]
+    fn claimed_nonces_stay_in_range() {
+        let mut state = MinerState::from_init(Principal::anonymous());
+        state.miner_id = u32::MAX;
+        let (start, end) = nonce_range(u32::MAX);
+        state.next_nonce = end - 10;
+        replace_state(state);
+
+        // Not enough nonces left before the end: wrap to the start.
+        assert_eq!(claim_nonces(100), start);
+        assert_eq!(claim_nonces(100), start + 100);
+
+        mutate_state(|s| {
+            s.next_nonce = end - 100;
+        });
+        assert_eq!(claim_nonces(100), end - 100);
+        assert_eq!(claim_nonces(1), start);
+    }
+}
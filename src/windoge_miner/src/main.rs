@@ -1,6 +1,29 @@
-use windoge_miner::{ mutate_state, read_state, replace_state, Block, MinerState };
-use candid::Principal;
-use ic_cdk::{ init, query, update };
+use windoge_miner::memory::{ load_state, save_state };
+use windoge_miner::{
+    load_block,
+    mark_push_mode,
+    mutate_state,
+    notify_backend,
+    record_cycles_out,
+    record_cycles_returned,
+    read_state,
+    replace_state,
+    resume_if_allowed,
+    set_status,
+    start_mining,
+    start_resume_checks,
+    start_template_polling,
+    stop_mining,
+    Block,
+    MinerState,
+    MiningStatus,
+    SpendingPolicy,
+    DECOMMISSION_RESERVE,
+    MIN_CYCLES,
+};
+use candid::{ CandidType, Principal };
+use ic_cdk::api::management_canister::main::{ deposit_cycles, CanisterIdRecord };
+use ic_cdk::{ init, post_upgrade, pre_upgrade, query, update };
 use ic_cdk::api::call::{msg_cycles_available128, msg_cycles_accept128};
 
 fn main() {}
@@ -8,6 +31,171 @@ fn main() {}
 #[init]
 fn init(owner: Principal) {
     replace_state(MinerState::from_init(owner));
+    start_template_polling();
+    start_resume_checks();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = read_state(|s| s.clone());
+    if let Err(e) = save_state(state) {
+        ic_cdk::trap(&format!("failed to save state: {:?}", e));
+    }
+}
+
+/// Miners installed before the state was persisted start over from `owner`.
+#[post_upgrade]
+fn post_upgrade(owner: Principal) {
+    let state = load_state().unwrap_or_else(|| MinerState::from_init(owner));
+    let was_mining = matches!(state.status, MiningStatus::Mining | MiningStatus::Submitting);
+    replace_state(state);
+
+    // The step timer did not survive the upgrade, schedule it again.
+    if was_mining {
+        mutate_state(|s| {
+            s.status = MiningStatus::Idle;
+            s.is_mining = false;
+        });
+        if read_state(|s| s.current_block.is_some()) {
+            start_mining();
+        }
+    }
+    start_template_polling();
+    start_resume_checks();
+}
+
+fn is_owner() -> Result<(), String> {
+    if ic_cdk::caller() != read_state(|s| s.owner) {
+        return Err("caller is not the owner".to_string());
+    }
+    Ok(())
+}
+
+#[update]
+fn start() -> Result<(), String> {
+    is_owner()?;
+    if read_state(|s| s.current_block.is_some()) {
+        start_mining();
+    } else {
+        // Nothing to mine yet, the next block starts the loop.
+        set_status(MiningStatus::Idle);
+    }
+    Ok(())
+}
+
+#[update]
+fn stop() -> Result<(), String> {
+    is_owner()?;
+    stop_mining();
+    Ok(())
+}
+
+/// Stops mining and tells the backend to stop pushing blocks.
+#[update]
+async fn pause() -> Result<(), String> {
+    is_owner()?;
+    stop_mining();
+    notify_backend("notify_miner_paused", (true,)).await
+}
+
+#[update]
+async fn resume() -> Result<(), String> {
+    is_owner()?;
+    notify_backend("notify_miner_paused", (false,)).await?;
+    start()
+}
+
+/// Caps the cycles mining may burn in total; `None` removes the cap.
+#[update]
+fn set_cycle_budget(budget: Option<u64>) -> Result<(), String> {
+    is_owner()?;
+    mutate_state(|s| {
+        s.cycle_budget = budget;
+    });
+    resume_if_allowed();
+    Ok(())
+}
+
+#[update]
+fn set_spending_policy(policy: SpendingPolicy) -> Result<(), String> {
+    is_owner()?;
+    if policy.reserve_floor < MIN_CYCLES {
+        return Err(format!("reserve_floor must be at least {}", MIN_CYCLES));
+    }
+    mutate_state(|s| {
+        s.spending_policy = policy;
+    });
+    resume_if_allowed();
+    Ok(())
+}
+
+#[derive(CandidType)]
+struct SpendingStatus {
+    status: MiningStatus,
+    balance: u64,
+    policy: SpendingPolicy,
+    cycle_budget: Option<u64>,
+    cycles_burned: u64,
+    cycles_burned_today: u64,
+    cycles_burned_block: u64,
+}
+
+#[query]
+fn get_spending_status() -> SpendingStatus {
+    let balance = ic_cdk::api::canister_balance();
+    read_state(|s| SpendingStatus {
+        status: s.status,
+        balance,
+        policy: s.spending_policy.clone(),
+        cycle_budget: s.cycle_budget,
+        cycles_burned: s.cycles_burned,
+        cycles_burned_today: s.cycles_burned_today,
+        cycles_burned_block: s.mining_start_cycles.saturating_sub(balance),
+    })
+}
+
+/// Sends cycles to canister `to`, by default everything above the spending policy's reserve floor.
+#[update]
+async fn withdraw_cycles(to: Principal, amount: Option<u64>) -> Result<u64, String> {
+    is_owner()?;
+    let reserve_floor = read_state(|s| s.spending_policy.reserve_floor);
+    let available = ic_cdk::api::canister_balance().saturating_sub(reserve_floor);
+    let amount = amount.unwrap_or(available);
+    if amount == 0 || amount > available {
+        return Err(format!("can withdraw at most {} cycles", available));
+    }
+
+    record_cycles_out(amount);
+    let target = CanisterIdRecord { canister_id: to };
+    if let Err((code, msg)) = deposit_cycles(target, amount as u128).await {
+        record_cycles_returned(amount);
+        return Err(format!("Error depositing cycles ({:?}): {}", code, msg));
+    }
+    ic_cdk::println!("Withdrew {} cycles to {}", amount, to.to_text());
+    Ok(amount)
+}
+
+/// Has the backend credit rewards to `account`; `None` pays the owner again.
+#[update]
+async fn set_reward_account(account: Option<Principal>) -> Result<(), String> {
+    is_owner()?;
+    notify_backend("notify_reward_account", (account,)).await?;
+    mutate_state(|s| {
+        s.reward_account = account;
+    });
+    Ok(())
+}
+
+/// Hands the miner to `new_owner`; the backend is updated first so both agree.
+#[update]
+async fn transfer_ownership(new_owner: Principal) -> Result<(), String> {
+    is_owner()?;
+    notify_backend("notify_owner_change", (new_owner,)).await?;
+    mutate_state(|s| {
+        s.owner = new_owner;
+        s.reward_account = None;
+    });
+    Ok(())
 }
 
 #[query]
@@ -26,48 +214,63 @@ fn receive() {
     });
 
     ic_cdk::println!("Received cycles!: {}", accepted_cycles);
+
+    resume_if_allowed();
 }
 
 #[update(hidden = true)]
-async fn push_block(block: Block, miner_id: u32) {
+async fn push_block(
+    block: Block,
+    miner_id: u32,
+    share_difficulty: Option<u32>,
+    template_id: Option<u64>
+) {
     let ledger_id = read_state(|s| s.ledger_id);
     assert_eq!(ic_cdk::caller(), ledger_id);
 
-    let should_start_mining = read_state(|s| !s.is_mining);
-    
+    mark_push_mode();
+    load_block(block, miner_id, share_difficulty, template_id);
+}
+
+#[update(hidden = true)]
+fn pause_mining() {
+    let ledger_id = read_state(|s| s.ledger_id);
+    assert_eq!(ic_cdk::caller(), ledger_id);
+
+    // Dropping the block makes the mining loop go idle at its next step.
     mutate_state(|s| {
-        s.current_block = Some(block.clone());
-        s.is_mining = true;
-        s.last_mining_timestamp = ic_cdk::api::time();
-        s.mining_start_cycles = ic_cdk::api::canister_balance();
-        s.mining_start_time = ic_cdk::api::time();
-        s.mining_temp_time = ic_cdk::api::time();
-        s.mining_temp_cycles = ic_cdk::api::canister_balance();
-        s.miner_id = miner_id;
-        s.mining_cycle = 0;
+        s.current_block = None;
     });
 
-    ic_cdk::println!("New block received: {:?}", block.header.height);
-
-    if should_start_mining {
-        ic_cdk::println!("Starting mining...");
-        ic_cdk::spawn(async {
-            match ic_cdk::api::call::call_raw(
-                ic_cdk::api::id(),
-                "find_solution",
-                candid::encode_args(()).unwrap(),
-                0
-            ).await {
-                Ok(_) => (),
-                Err(err) => {
-                    ic_cdk::println!("Error calling self: {:?}", err);
-                    mutate_state(|s| {
-                        s.is_mining = false;
-                    });
-                }
-            }
-        });
+    ic_cdk::println!("Mining paused by backend");
+}
+
+/// Sends all but `DECOMMISSION_RESERVE` cycles to `to`, through `receive` when it
+/// is a miner, before the backend stops and deletes this canister. Returns the cycles sent.
+#[update(hidden = true)]
+async fn decommission(to: Principal, to_miner: bool) -> Result<u64, String> {
+    if ic_cdk::caller() != read_state(|s| s.ledger_id) {
+        return Err("caller is not the backend".to_string());
+    }
+
+    stop_mining();
+    let amount = ic_cdk::api::canister_balance().saturating_sub(DECOMMISSION_RESERVE);
+    if amount > 0 {
+        record_cycles_out(amount);
+        // Another miner counts cycles sent to its `receive` as a top-up.
+        let res = if to_miner {
+            ic_cdk::api::call
+                ::call_with_payment128::<_, ()>(to, "receive", (), amount as u128).await
+        } else {
+            deposit_cycles(CanisterIdRecord { canister_id: to }, amount as u128).await
+        };
+        if let Err((code, msg)) = res {
+            record_cycles_returned(amount);
+            return Err(format!("Error depositing cycles ({:?}): {}", code, msg));
+        }
     }
+    ic_cdk::println!("Decommissioned, sent {} cycles to {}", amount, to.to_text());
+    Ok(amount)
 }
 
 #[query]
@@ -0,0 +1,251 @@
+use ic_stable_structures::log::WriteError;
+use ic_stable_structures::memory_manager::{ MemoryId, MemoryManager as MM, VirtualMemory };
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{ DefaultMemoryImpl as DefMem, StableLog, Storable };
+use serde::{ Deserialize, Serialize };
+use std::borrow::Cow;
+use std::cell::RefCell;
+use crate::MinerState;
+
+/// Bump when a `MinerState` change needs more than `#[serde(default)]`, and
+/// add the fixup to `migrate`.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct StoredState {
+    version: u32,
+    state: MinerState,
+}
+
+struct Cbor<T>(pub T) where T: serde::Serialize + serde::de::DeserializeOwned;
+
+impl<T> Storable for Cbor<T> where T: serde::Serialize + serde::de::DeserializeOwned {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(&self.0, &mut buf).unwrap();
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(ciborium::de::from_reader(bytes.as_ref()).unwrap())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+const STATE_INDX_MEM_ID: MemoryId = MemoryId::new(0);
+const STATE_DATA_MEM_ID: MemoryId = MemoryId::new(1);
+
+type VM = VirtualMemory<DefMem>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MM<DefMem>> = RefCell::new(
+        MM::init(DefMem::default())
+    );
+
+    static STATE: RefCell<StableLog<Cbor<StoredState>, VM, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableLog::init(
+                mm.borrow().get(STATE_INDX_MEM_ID),
+                mm.borrow().get(STATE_DATA_MEM_ID)
+            ).expect("failed to initialize the state log")
+        )
+    });
+}
+
+pub fn save_state(state: MinerState) -> Result<u64, WriteError> {
+    STATE.with(|s| s.borrow_mut().append(&Cbor(StoredState { version: STATE_VERSION, state })))
+}
+
+/// The last saved state, migrated to `STATE_VERSION`. `None` for miners
+/// installed before the state was persisted.
+pub fn load_state() -> Option<MinerState> {
+    let stored = STATE.with(|s| {
+        let log = s.borrow();
+        let len = log.len();
+        if len == 0 { None } else { log.get(len - 1).map(|b| b.0) }
+    })?;
+    match migrate(stored) {
+        Ok(state) => Some(state),
+        Err(e) => ic_cdk::trap(&e),
+    }
+}
+
+/// Fields added since version 1 decode through `#[serde(default)]`; fixups
+/// that need more than a default go here.
+fn migrate(stored: StoredState) -> Result<MinerState, String> {
+    if stored.version > STATE_VERSION {
+        return Err(
+            format!(
+                "stored state version {} is newer than supported version {}",
+                stored.version,
+                STATE_VERSION
+            )
+        );
+    }
+    Ok(stored.state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Block, BlockHeader, MiningStatus, SpendingPolicy, Transaction };
+    use candid::Principal;
+
+    fn populated_state() -> MinerState {
+        let owner = Principal::from_slice(&[1; 29]);
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                height: 42,
+                prev_hash: u128::MAX - 1,
+                merkle_root: 7,
+                timestamp: 1_700_000_000_000_000_000,
+                difficulty: 24,
+            },
+            transactions: vec![Transaction {
+                sender: owner,
+                recipient: Principal::from_slice(&[2; 29]),
+                amount: 5,
+                timestamp: 1_700_000_000_000_000_000,
+            }],
+            nonce: 3 << 64,
+            hash: 0,
+        };
+
+        MinerState {
+            cycles_burned: 1_000,
+            blocks_mined: 2,
+            last_mining_timestamp: 3,
+            is_mining: true,
+            status: MiningStatus::Mining,
+            time_spent_mining: 4,
+            mining_start_time: 5,
+            mining_start_cycles: 6,
+            mining_temp_time: 7,
+            mining_temp_cycles: 8,
+            current_block: Some(block),
+            miner_id: 3,
+            next_nonce: (3 << 64) + 10_000,
+            mining_cycle: 9,
+            cycle_budget: Some(10),
+            spending_policy: SpendingPolicy {
+                max_cycles_per_block: Some(11),
+                max_cycles_per_day: Some(12),
+                reserve_floor: 13,
+            },
+            spending_day: 14,
+            cycles_burned_today: 15,
+            reward_account: Some(Principal::from_slice(&[3; 29])),
+            chunk_size: 16,
+            instructions_per_hash: 17,
+            share_difficulty: Some(18),
+            template_id: Some(19),
+            ..MinerState::from_init(owner)
+        }
+    }
+
+    fn encode<T: Serialize + serde::de::DeserializeOwned>(value: T) -> Vec<u8> {
+        Cbor(value).to_bytes().into_owned()
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let state = populated_state();
+        let bytes = encode(StoredState { version: STATE_VERSION, state: state.clone() });
+
+        let stored = Cbor::<StoredState>::from_bytes(Cow::Borrowed(&bytes)).0;
+        assert_eq!(stored.version, STATE_VERSION);
+        let decoded = migrate(stored).unwrap();
+
+        assert_eq!(decoded.owner, state.owner);
+        assert_eq!(decoded.status, MiningStatus::Mining);
+        assert_eq!(decoded.spending_policy, state.spending_policy);
+        assert_eq!(decoded.next_nonce, state.next_nonce);
+        assert_eq!(decoded.current_block.as_ref().map(|b| b.header.height), Some(42));
+        // Every field survives: encoding the decoded state gives the same bytes.
+        assert_eq!(encode(StoredState { version: STATE_VERSION, state: decoded }), bytes);
+    }
+
+    #[test]
+    fn save_and_load_return_last_state() {
+        let mut state = populated_state();
+        save_state(state.clone()).unwrap();
+        state.blocks_mined = 100;
+        save_state(state).unwrap();
+
+        assert_eq!(load_state().map(|s| s.blocks_mined), Some(100));
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let stored = StoredState { version: STATE_VERSION + 1, state: populated_state() };
+        assert!(migrate(stored).is_err());
+    }
+
+    /// `MinerState` as the first miner release had it.
+    #[derive(Serialize)]
+    struct StateV0 {
+        ledger_id: Principal,
+        owner: Principal,
+        cycles_burned: u64,
+        blocks_mined: u64,
+        last_mining_timestamp: u64,
+        is_mining: bool,
+        time_spent_mining: u64,
+        mining_start_time: u64,
+        mining_start_cycles: u64,
+        mining_temp_time: u64,
+        mining_temp_cycles: u64,
+        current_block: Option<Block>,
+        miner_id: u32,
+        mining_cycle: u64,
+    }
+
+    #[derive(Serialize)]
+    struct StoredStateV0 {
+        version: u32,
+        state: StateV0,
+    }
+
+    #[test]
+    fn older_schema_decodes_with_defaults() {
+        let old = populated_state();
+        let bytes = {
+            let mut buf = vec![];
+            ciborium::ser::into_writer(
+                &(StoredStateV0 {
+                    version: 1,
+                    state: StateV0 {
+                        ledger_id: old.ledger_id,
+                        owner: old.owner,
+                        cycles_burned: old.cycles_burned,
+                        blocks_mined: old.blocks_mined,
+                        last_mining_timestamp: old.last_mining_timestamp,
+                        is_mining: false,
+                        time_spent_mining: old.time_spent_mining,
+                        mining_start_time: old.mining_start_time,
+                        mining_start_cycles: old.mining_start_cycles,
+                        mining_temp_time: old.mining_temp_time,
+                        mining_temp_cycles: old.mining_temp_cycles,
+                        current_block: None,
+                        miner_id: old.miner_id,
+                        mining_cycle: old.mining_cycle,
+                    },
+                }),
+                &mut buf
+            ).unwrap();
+            buf
+        };
+
+        let state = migrate(Cbor::<StoredState>::from_bytes(Cow::Owned(bytes)).0).unwrap();
+        assert_eq!(state.owner, old.owner);
+        assert_eq!(state.blocks_mined, old.blocks_mined);
+        assert_eq!(state.status, MiningStatus::Idle);
+        assert_eq!(state.spending_policy, SpendingPolicy::default());
+        assert_eq!(state.next_nonce, 0);
+        assert_eq!(state.cycle_budget, None);
+        assert_eq!(state.reward_account, None);
+        assert_eq!(state.template_id, None);
+    }
+}
@@ -0,0 +1,187 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use crate::memory::{ block_count, blocks_range, get_block, stats_range };
+use crate::{ read_state, SEC_NANOS };
+
+/// One mined block as seen by the dashboard. `block_time` is measured between
+/// the backend's own block timestamps rather than the miner-reported solve time.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BlockPoint {
+    pub height: u64,
+    pub timestamp: u64,
+    pub difficulty: u32,
+    pub block_time: u64,
+    pub solve_time: u64,
+    pub cycles_burned: u64,
+    pub miner: Principal,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct HashratePoint {
+    pub height: u64,
+    pub timestamp: u64,
+    /// Estimated hashes per second over the trailing window ending at `height`.
+    pub hashrate: u64,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DifficultyPoint {
+    pub height: u64,
+    pub timestamp: u64,
+    pub difficulty: u32,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct BlockTimeSummary {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+/// Mined blocks at heights `start..start + length`. The genesis block has no
+/// stats and is never returned.
+pub fn block_points(start: u64, length: u64) -> Vec<BlockPoint> {
+    let start = std::cmp::max(start, 1);
+    // One extra block in front to measure the first interval.
+    let blocks = blocks_range(start - 1, length.saturating_add(1));
+    let stats = stats_range(start - 1, length);
+
+    blocks
+        .windows(2)
+        .zip(stats)
+        .map(|(pair, stats)| BlockPoint {
+            height: pair[1].header.height,
+            timestamp: pair[1].header.timestamp,
+            difficulty: pair[1].header.difficulty,
+            // The genesis block carries no timestamp, fall back to the reported solve time.
+            block_time: if pair[0].header.timestamp == 0 {
+                stats.solve_time
+            } else {
+                pair[1].header.timestamp.saturating_sub(pair[0].header.timestamp)
+            },
+            solve_time: stats.solve_time,
+            cycles_burned: stats.cycles_burned,
+            miner: stats.miner,
+        })
+        .collect()
+}
+
+/// Expected hashes to find a block at `difficulty`.
+fn expected_work(difficulty: u32) -> u128 {
+    1_u128 << std::cmp::min(difficulty, 127)
+}
+
+/// Network hashrate at each height in `start..start + length`, averaged over the
+/// `window` blocks ending there.
+pub fn hashrate_history(start: u64, length: u64, window: u64) -> Vec<HashratePoint> {
+    let window = std::cmp::max(window, 1);
+    let first = std::cmp::max(start, 1);
+    let lookback = std::cmp::min(first - 1, window - 1);
+    let points = block_points(first - lookback, length.saturating_add(lookback));
+
+    (lookback as usize..points.len())
+        .map(|i| {
+            let from = (i + 1).saturating_sub(window as usize);
+            let span = &points[from..=i];
+            let work: u128 = span
+                .iter()
+                .map(|p| expected_work(p.difficulty))
+                .fold(0, |total, w| total.saturating_add(w));
+            let secs: u64 = span.iter().map(|p| p.block_time).sum::<u64>() / SEC_NANOS;
+            HashratePoint {
+                height: points[i].height,
+                timestamp: points[i].timestamp,
+                hashrate: std::cmp::min(work / std::cmp::max(secs, 1) as u128, u64::MAX as u128) as u64,
+            }
+        })
+        .collect()
+}
+
+/// Heights in `start..start + length` where the difficulty changed, plus the first one.
+pub fn difficulty_history(start: u64, length: u64) -> Vec<DifficultyPoint> {
+    let mut previous = start
+        .checked_sub(1)
+        .and_then(get_block)
+        .map(|block| block.header.difficulty);
+
+    let mut history = Vec::new();
+    for block in blocks_range(start, length) {
+        if previous != Some(block.header.difficulty) {
+            history.push(DifficultyPoint {
+                height: block.header.height,
+                timestamp: block.header.timestamp,
+                difficulty: block.header.difficulty,
+            });
+        }
+        previous = Some(block.header.difficulty);
+    }
+    history
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile(sorted: &[u64], percent: u64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((sorted.len() as u64) * percent).div_ceil(100);
+    sorted[(std::cmp::max(rank, 1) - 1) as usize]
+}
+
+pub fn block_time_summary(start: u64, length: u64) -> BlockTimeSummary {
+    let mut times: Vec<u64> = block_points(start, length)
+        .into_iter()
+        .map(|p| p.block_time)
+        .collect();
+    if times.is_empty() {
+        return BlockTimeSummary::default();
+    }
+    times.sort_unstable();
+
+    let total: u128 = times.iter().map(|t| *t as u128).sum();
+    BlockTimeSummary {
+        count: times.len() as u64,
+        min: times[0],
+        max: times[times.len() - 1],
+        mean: (total / (times.len() as u128)) as u64,
+        p50: percentile(&times, 50),
+        p90: percentile(&times, 90),
+        p99: percentile(&times, 99),
+    }
+}
+
+/// Blocks mined per owner at heights `start..start + length`, decommissioned
+/// miners included. Miners with no known owner are counted under their own principal.
+pub fn blocks_per_owner(start: u64, length: u64) -> Vec<(Principal, u64)> {
+    let points = block_points(start, length);
+    let mut counts: BTreeMap<Principal, u64> = BTreeMap::new();
+    read_state(|s| {
+        for point in points {
+            let owner = s.historical_owner(&point.miner).unwrap_or(point.miner);
+            *counts.entry(owner).or_insert(0) += 1;
+        }
+    });
+    counts.into_iter().collect()
+}
+
+/// First height whose block was created at or after `timestamp`; lets time
+/// windows be turned into height ranges for the queries above.
+pub fn height_at(timestamp: u64) -> u64 {
+    let (mut low, mut high) = (0, block_count());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match get_block(mid) {
+            Some(block) if block.header.timestamp < timestamp => {
+                low = mid + 1;
+            }
+            _ => {
+                high = mid;
+            }
+        }
+    }
+    low
+}
@@ -0,0 +1,268 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use crate::SEC_NANOS;
+
+pub const COINBASE_REWARDS: u64 = 60_000_000_000;
+pub const BLOCK_HALVING: u64 = 17_500;
+pub const INIT_DIFFICULTY: u32 = 26;
+pub const MIN_DIFFICULTY: u32 = 24;
+pub const MAX_DIFFICULTY: u32 = 48;
+pub const BLOCK_TIME: u64 = 300 * SEC_NANOS; // 5 minutes
+pub const TRANSACTION_LIMIT: u64 = 150;
+pub const BLOCK_BATCH_SIZE: u64 = 100;
+pub const MINER_CREATION_AMOUNT: u64 = 1500000000; // 15 Windoge98
+pub const MINER_CREATION_CYCLES: u64 = 2_500_000_000_000;
+pub const BIL_LEDGER_ID: &str = "ktra4-taaaa-aaaag-atveq-cai";
+pub const WINDOGE_LEDGER_ID: &str = "rh2pm-ryaaa-aaaan-qeniq-cai";
+pub const PPLNS_WINDOW: u64 = 5_000;
+pub const SHARE_DIFFICULTY: u32 = 20;
+pub const MIN_SHARE_INTERVAL: u64 = SEC_NANOS;
+
+/// What `create_block` does when the mempool is empty.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum EmptyBlockPolicy {
+    /// Wait for transactions.
+    Skip,
+    /// Mine coinbase-only blocks, block production never waits on traffic.
+    Always,
+    /// Mine a coinbase-only block once no block was created for this many nanoseconds.
+    AfterIdle(u64),
+}
+
+/// How miners learn about new candidate blocks.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum DistributionMode {
+    /// The backend calls `push_block` on every miner, in batches of `block_batch_size`.
+    Push,
+    /// Miners poll `get_block_template`; the backend makes no calls per block.
+    Pull,
+}
+
+/// Economic and deployment parameters of the backend, set at install and upgrade time.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub coinbase_rewards: u64,
+    pub block_halving: u64,
+    /// Floor of the block reward once halvings bring it below; 0 means the
+    /// emission ends after the final halving.
+    pub tail_emission: u64,
+    pub init_difficulty: u32,
+    pub min_difficulty: u32,
+    pub max_difficulty: u32,
+    pub block_time: u64,
+    pub transaction_limit: u64,
+    pub block_batch_size: u64,
+    pub distribution_mode: DistributionMode,
+    pub empty_block_policy: EmptyBlockPolicy,
+    pub miner_creation_amount: u64,
+    pub miner_creation_cycles: u64,
+    /// Share of the miner creation payment burned after a spawn.
+    pub spawn_burn_percent: u64,
+    /// Share of a top-up payment burned.
+    pub topup_burn_percent: u64,
+    /// Share of a top-up payment's value forwarded to the miner as cycles.
+    pub topup_cycles_percent: u64,
+    pub bil_ledger_id: Principal,
+    pub windoge_ledger_id: Principal,
+    /// SNS/DAO principal with controller rights over every admin endpoint.
+    pub governance_principal: Option<Principal>,
+    /// Split each block's coinbase over the owners of the last `pplns_window` shares.
+    pub pool_enabled: bool,
+    pub pplns_window: u64,
+    /// Leading zero bits a share needs; shares feed hashrate estimates even without the pool.
+    pub share_difficulty: u32,
+    /// Minimum time between two shares of the same miner.
+    pub min_share_interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            coinbase_rewards: COINBASE_REWARDS,
+            block_halving: BLOCK_HALVING,
+            tail_emission: 0,
+            init_difficulty: INIT_DIFFICULTY,
+            min_difficulty: MIN_DIFFICULTY,
+            max_difficulty: MAX_DIFFICULTY,
+            block_time: BLOCK_TIME,
+            transaction_limit: TRANSACTION_LIMIT,
+            block_batch_size: BLOCK_BATCH_SIZE,
+            distribution_mode: DistributionMode::Push,
+            empty_block_policy: EmptyBlockPolicy::Skip,
+            miner_creation_amount: MINER_CREATION_AMOUNT,
+            miner_creation_cycles: MINER_CREATION_CYCLES,
+            spawn_burn_percent: 40,
+            topup_burn_percent: 10,
+            topup_cycles_percent: 80,
+            bil_ledger_id: Principal::from_text(BIL_LEDGER_ID).unwrap(),
+            windoge_ledger_id: Principal::from_text(WINDOGE_LEDGER_ID).unwrap(),
+            governance_principal: None,
+            pool_enabled: false,
+            pplns_window: PPLNS_WINDOW,
+            share_difficulty: SHARE_DIFFICULTY,
+            min_share_interval: MIN_SHARE_INTERVAL,
+        }
+    }
+}
+
+/// Install arguments; every field left out keeps its current (or default) value.
+#[derive(Clone, CandidType, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct InitArgs {
+    pub coinbase_rewards: Option<u64>,
+    pub block_halving: Option<u64>,
+    pub tail_emission: Option<u64>,
+    pub init_difficulty: Option<u32>,
+    pub min_difficulty: Option<u32>,
+    pub max_difficulty: Option<u32>,
+    pub block_time: Option<u64>,
+    pub transaction_limit: Option<u64>,
+    pub block_batch_size: Option<u64>,
+    pub distribution_mode: Option<DistributionMode>,
+    pub empty_block_policy: Option<EmptyBlockPolicy>,
+    pub miner_creation_amount: Option<u64>,
+    pub miner_creation_cycles: Option<u64>,
+    pub spawn_burn_percent: Option<u64>,
+    pub topup_burn_percent: Option<u64>,
+    pub topup_cycles_percent: Option<u64>,
+    pub bil_ledger_id: Option<Principal>,
+    pub windoge_ledger_id: Option<Principal>,
+    pub governance_principal: Option<Principal>,
+    pub pool_enabled: Option<bool>,
+    pub pplns_window: Option<u64>,
+    pub share_difficulty: Option<u32>,
+    pub min_share_interval: Option<u64>,
+}
+
+pub type UpgradeArgs = InitArgs;
+
+impl Config {
+    pub fn apply(&mut self, args: InitArgs) {
+        if let Some(value) = args.coinbase_rewards {
+            self.coinbase_rewards = value;
+        }
+        if let Some(value) = args.block_halving {
+            self.block_halving = value;
+        }
+        if let Some(value) = args.tail_emission {
+            self.tail_emission = value;
+        }
+        if let Some(value) = args.init_difficulty {
+            self.init_difficulty = value;
+        }
+        if let Some(value) = args.min_difficulty {
+            self.min_difficulty = value;
+        }
+        if let Some(value) = args.max_difficulty {
+            self.max_difficulty = value;
+        }
+        if let Some(value) = args.block_time {
+            self.block_time = value;
+        }
+        if let Some(value) = args.transaction_limit {
+            self.transaction_limit = value;
+        }
+        if let Some(value) = args.block_batch_size {
+            self.block_batch_size = value;
+        }
+        if let Some(value) = args.distribution_mode {
+            self.distribution_mode = value;
+        }
+        if let Some(value) = args.empty_block_policy {
+            self.empty_block_policy = value;
+        }
+        if let Some(value) = args.miner_creation_amount {
+            self.miner_creation_amount = value;
+        }
+        if let Some(value) = args.miner_creation_cycles {
+            self.miner_creation_cycles = value;
+        }
+        if let Some(value) = args.spawn_burn_percent {
+            self.spawn_burn_percent = value;
+        }
+        if let Some(value) = args.topup_burn_percent {
+            self.topup_burn_percent = value;
+        }
+        if let Some(value) = args.topup_cycles_percent {
+            self.topup_cycles_percent = value;
+        }
+        if let Some(value) = args.bil_ledger_id {
+            self.bil_ledger_id = value;
+        }
+        if let Some(value) = args.windoge_ledger_id {
+            self.windoge_ledger_id = value;
+        }
+        if let Some(value) = args.governance_principal {
+            self.governance_principal = Some(value);
+        }
+        if let Some(value) = args.pool_enabled {
+            self.pool_enabled = value;
+        }
+        if let Some(value) = args.pplns_window {
+            self.pplns_window = value;
+        }
+        if let Some(value) = args.share_difficulty {
+            self.share_difficulty = value;
+        }
+        if let Some(value) = args.min_share_interval {
+            self.min_share_interval = value;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.coinbase_rewards == 0 {
+            return Err("coinbase_rewards must be greater than 0".to_string());
+        }
+        if self.block_halving == 0 {
+            return Err("block_halving must be greater than 0".to_string());
+        }
+        if self.tail_emission > self.coinbase_rewards {
+            return Err("tail_emission must not exceed coinbase_rewards".to_string());
+        }
+        if self.block_time == 0 {
+            return Err("block_time must be greater than 0".to_string());
+        }
+        if self.max_difficulty > 128 {
+            return Err("max_difficulty must not exceed 128".to_string());
+        }
+        if
+            self.min_difficulty > self.init_difficulty ||
+            self.init_difficulty > self.max_difficulty
+        {
+            return Err("difficulty bounds must satisfy min <= init <= max".to_string());
+        }
+        if self.transaction_limit == 0 {
+            return Err("transaction_limit must be greater than 0".to_string());
+        }
+        if self.block_batch_size == 0 {
+            return Err("block_batch_size must be greater than 0".to_string());
+        }
+        if self.miner_creation_amount == 0 {
+            return Err("miner_creation_amount must be greater than 0".to_string());
+        }
+        if self.spawn_burn_percent > 100 {
+            return Err("spawn_burn_percent must not exceed 100".to_string());
+        }
+        if self.topup_burn_percent.saturating_add(self.topup_cycles_percent) > 100 {
+            return Err("topup_burn_percent + topup_cycles_percent must not exceed 100".to_string());
+        }
+        if self.bil_ledger_id == Principal::anonymous() {
+            return Err("bil_ledger_id must be set".to_string());
+        }
+        if self.windoge_ledger_id == Principal::anonymous() {
+            return Err("windoge_ledger_id must be set".to_string());
+        }
+        if self.pplns_window == 0 {
+            return Err("pplns_window must be greater than 0".to_string());
+        }
+        if self.share_difficulty == 0 || self.share_difficulty >= self.min_difficulty {
+            return Err("share_difficulty must be between 1 and min_difficulty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// `percent` of `amount`, computed without overflowing.
+pub fn percent_of(amount: u64, percent: u64) -> u64 {
+    (((amount as u128) * (percent as u128)) / 100) as u64
+}
@@ -0,0 +1,40 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// A decommissioned miner. Its mined blocks and burned cycles stay in the stats.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RetiredMiner {
+    pub owner: Principal,
+    /// Cycles the miner sent to `cycles_to` before it was deleted.
+    pub cycles_refunded: u64,
+    pub cycles_to: Principal,
+    pub retired_at: u64,
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<Principal>> = RefCell::default();
+}
+
+/// Prevents two calls from winding down the same miner concurrently.
+pub struct DecommissionGuard(Principal);
+
+impl DecommissionGuard {
+    pub fn new(miner: Principal) -> Result<Self, String> {
+        IN_FLIGHT.with(|s| {
+            if !s.borrow_mut().insert(miner) {
+                return Err("decommission already in progress".to_string());
+            }
+            Ok(Self(miner))
+        })
+    }
+}
+
+impl Drop for DecommissionGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|s| {
+            s.borrow_mut().remove(&self.0);
+        });
+    }
+}
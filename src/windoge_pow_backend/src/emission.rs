@@ -0,0 +1,227 @@
+use candid::CandidType;
+use serde::{ Deserialize, Serialize };
+use crate::config::Config;
+use crate::State;
+
+/// Rewards of one halving epoch. Epoch `e` covers heights
+/// `e * block_halving..(e + 1) * block_halving`; height 0 is the genesis block and pays nothing.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct EmissionEpoch {
+    pub epoch: u64,
+    pub start_height: u64,
+    pub end_height: u64,
+    pub reward: u64,
+    /// Supply emitted once the last block of this epoch is mined.
+    pub cumulative_supply: u64,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SupplyInfo {
+    pub total_minted: u64,
+    pub total_deposited: u64,
+    /// Balances moved back out to the BIL ledger by completed withdrawals.
+    pub total_withdrawn: u64,
+    pub circulating_supply: u64,
+    /// `None` when tail emission makes the supply unbounded.
+    pub max_supply: Option<u64>,
+    pub current_reward: u64,
+    pub current_epoch: u64,
+    pub final_epoch: Option<u64>,
+}
+
+/// Block reward during `epoch`. The coinbase halves every epoch until it reaches
+/// `tail_emission`, which is then paid forever; without a tail the reward is 0
+/// from the final epoch on instead of wrapping the shift.
+pub fn reward_for_epoch(config: &Config, epoch: u64) -> u64 {
+    let halved = if epoch >= 64 { 0 } else { config.coinbase_rewards >> epoch };
+    std::cmp::max(halved, config.tail_emission)
+}
+
+pub fn epoch_at(config: &Config, height: u64) -> u64 {
+    height / config.block_halving
+}
+
+/// Reward of the block at `height`.
+pub fn reward_at(config: &Config, height: u64) -> u64 {
+    if height == 0 {
+        return 0;
+    }
+    reward_for_epoch(config, epoch_at(config, height))
+}
+
+/// Rewards used to be derived from the number of blocks counted in
+/// `miner_to_mined_block` rather than from the height. Describes any drift
+/// between that count, the state height and the stored chain, `None` if they agree.
+pub fn reward_migration_report(state: &State, chain_height: u64) -> Option<String> {
+    let mined_blocks = state.mined_block_count();
+    if mined_blocks == state.block_height && state.block_height == chain_height {
+        return None;
+    }
+
+    let old_epoch = mined_blocks / state.config.block_halving;
+    let new_epoch = epoch_at(&state.config, state.block_height);
+    Some(
+        format!(
+            "mined block count {} (epoch {}), state height {} (epoch {}), chain height {}; reward {} -> {}",
+            mined_blocks,
+            old_epoch,
+            state.block_height,
+            new_epoch,
+            chain_height,
+            reward_for_epoch(&state.config, old_epoch),
+            reward_at(&state.config, state.block_height)
+        )
+    )
+}
+
+/// First epoch paying no reward, `None` with tail emission.
+pub fn final_epoch(config: &Config) -> Option<u64> {
+    if config.tail_emission > 0 {
+        return None;
+    }
+    Some((64 - config.coinbase_rewards.leading_zeros()) as u64)
+}
+
+/// Heights of epoch `epoch` that pay a reward, as a `start..end` range.
+fn epoch_heights(config: &Config, epoch: u64) -> (u64, u64) {
+    let start = std::cmp::max(epoch.saturating_mul(config.block_halving), 1);
+    let end = epoch.saturating_add(1).saturating_mul(config.block_halving);
+    (start, end)
+}
+
+/// Supply emitted by the blocks at heights `1..=height`.
+pub fn emitted_until(config: &Config, height: u64) -> u64 {
+    let mut total: u64 = 0;
+    for epoch in 0..64 {
+        let (start, end) = epoch_heights(config, epoch);
+        if start > height {
+            return total;
+        }
+        let blocks = std::cmp::min(end, height.saturating_add(1)) - start;
+        total = total.saturating_add(blocks.saturating_mul(reward_for_epoch(config, epoch)));
+    }
+
+    // Past 64 halvings only the tail is left.
+    let (start, _) = epoch_heights(config, 64);
+    if start <= height {
+        total = total.saturating_add((height - start + 1).saturating_mul(config.tail_emission));
+    }
+    total
+}
+
+pub fn max_supply(config: &Config) -> Option<u64> {
+    final_epoch(config).map(|last|
+        emitted_until(config, last.saturating_mul(config.block_halving).saturating_sub(1))
+    )
+}
+
+/// Epochs `start..start + length` of the emission schedule.
+pub fn emission_curve(config: &Config, start: u64, length: u64) -> Vec<EmissionEpoch> {
+    let end = match final_epoch(config) {
+        // Include the final epoch so the curve visibly reaches zero.
+        Some(last) => std::cmp::min(start.saturating_add(length), last.saturating_add(1)),
+        None => start.saturating_add(length),
+    };
+
+    (start..end)
+        .map(|epoch| {
+            let (start_height, end_height) = epoch_heights(config, epoch);
+            EmissionEpoch {
+                epoch,
+                start_height,
+                end_height,
+                reward: reward_for_epoch(config, epoch),
+                cumulative_supply: emitted_until(config, end_height - 1),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ BLOCK_HALVING, COINBASE_REWARDS };
+
+    fn small_config(tail_emission: u64) -> Config {
+        Config {
+            coinbase_rewards: 1_000,
+            block_halving: 10,
+            tail_emission,
+            ..Config::default()
+        }
+    }
+
+    /// The reward as the first release computed it, from the height instead of the block count.
+    fn baseline_reward(config: &Config, height: u64) -> u64 {
+        let epoch = height / config.block_halving;
+        if height == 0 || epoch >= 64 { 0 } else { config.coinbase_rewards >> epoch }
+    }
+
+    fn baseline_emitted(config: &Config, height: u64) -> u64 {
+        (1..=height).map(|h| baseline_reward(config, h)).sum()
+    }
+
+    #[test]
+    fn reward_matches_baseline_across_epochs() {
+        let config = Config::default();
+        for epoch in [0, 1, 2, 10, 34, 35, 36, 63, 64, 65] {
+            let start = epoch * BLOCK_HALVING;
+            for height in [start.saturating_sub(1), start, start + 1, start + BLOCK_HALVING - 1] {
+                assert_eq!(
+                    reward_at(&config, height),
+                    baseline_reward(&config, height),
+                    "height {}",
+                    height
+                );
+            }
+        }
+        assert_eq!(reward_at(&config, 0), 0);
+        assert_eq!(reward_at(&config, 1), COINBASE_REWARDS);
+        assert_eq!(reward_at(&config, BLOCK_HALVING), COINBASE_REWARDS / 2);
+    }
+
+    #[test]
+    fn emitted_until_matches_baseline_sum() {
+        let config = small_config(0);
+        for height in 0..150 {
+            assert_eq!(emitted_until(&config, height), baseline_emitted(&config, height));
+        }
+        // Heights 1..10 pay 1000 each, 10..20 pay 500 each.
+        assert_eq!(emitted_until(&config, 9), 9_000);
+        assert_eq!(emitted_until(&config, 10), 9_500);
+        assert_eq!(emitted_until(&config, 19), 14_000);
+    }
+
+    #[test]
+    fn max_supply_is_everything_before_final_epoch() {
+        let config = small_config(0);
+        // 1000 has 10 significant bits, so epoch 10 pays nothing.
+        assert_eq!(final_epoch(&config), Some(10));
+        assert_eq!(reward_for_epoch(&config, 9), 1);
+        assert_eq!(reward_for_epoch(&config, 10), 0);
+        assert_eq!(max_supply(&config), Some(baseline_emitted(&config, 99)));
+        assert_eq!(max_supply(&config), Some(emitted_until(&config, 1_000)));
+
+        let config = Config::default();
+        let last = final_epoch(&config).unwrap();
+        assert_eq!(reward_for_epoch(&config, last - 1), 1);
+        assert_eq!(
+            max_supply(&config),
+            Some(emitted_until(&config, (last + 10) * BLOCK_HALVING))
+        );
+    }
+
+    #[test]
+    fn tail_emission_floors_the_reward() {
+        let config = small_config(100);
+        assert_eq!(final_epoch(&config), None);
+        assert_eq!(max_supply(&config), None);
+        assert_eq!(reward_for_epoch(&config, 3), 125);
+        assert_eq!(reward_for_epoch(&config, 4), 100);
+        assert_eq!(reward_for_epoch(&config, 100), 100);
+        assert_eq!(
+            emitted_until(&config, 59),
+            (1..=59).map(|h| std::cmp::max(baseline_reward(&config, h), 100)).sum::<u64>()
+        );
+    }
+}
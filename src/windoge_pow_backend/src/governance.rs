@@ -0,0 +1,53 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use crate::memory::{ append_audit_entry, get_role };
+use crate::read_state;
+
+/// Privileged roles, ordered from least to most powerful. Every role
+/// implies the permissions of the roles below it.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Role {
+    Auditor,
+    Operator,
+    Admin,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub action: String,
+    pub details: String,
+}
+
+/// Canister controllers and the configured governance (SNS/DAO) principal
+/// sit above every role.
+pub fn is_controller(caller: &Principal) -> bool {
+    ic_cdk::api::is_controller(caller) ||
+        read_state(|s| s.config.governance_principal == Some(*caller))
+}
+
+pub fn role_of(caller: &Principal) -> Option<Role> {
+    if is_controller(caller) {
+        return Some(Role::Admin);
+    }
+    get_role(*caller)
+}
+
+pub fn authorize(caller: &Principal, required: Role) -> Result<(), String> {
+    match role_of(caller) {
+        Some(role) if role >= required => Ok(()),
+        _ => Err("caller is not allowed".to_string()),
+    }
+}
+
+/// Appends an entry to the audit log. Every privileged action goes through here.
+pub fn audit(caller: Principal, action: &str, details: String) {
+    ic_cdk::println!("Audit: {} by {}: {}", action, caller.to_text(), details);
+    let _ = append_audit_entry(AuditEntry {
+        timestamp: ic_cdk::api::time(),
+        caller,
+        action: action.to_string(),
+        details,
+    });
+}
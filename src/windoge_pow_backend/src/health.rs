@@ -0,0 +1,99 @@
+use candid::{ CandidType, Principal };
+use ic_cdk::api::management_canister::main::CanisterStatusType;
+use serde::{ Deserialize, Serialize };
+
+/// Failed pushes or status checks in a row after which a miner gets no more blocks.
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+/// Balance below which a miner is too close to freezing to mine, the miner's lowest reserve floor.
+pub const MIN_MINER_CYCLES: u64 = 100_000_000_000;
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum CanisterRunStatus {
+    Running,
+    Stopping,
+    Stopped,
+}
+
+impl From<CanisterStatusType> for CanisterRunStatus {
+    fn from(status: CanisterStatusType) -> Self {
+        match status {
+            CanisterStatusType::Running => Self::Running,
+            CanisterStatusType::Stopping => Self::Stopping,
+            CanisterStatusType::Stopped => Self::Stopped,
+        }
+    }
+}
+
+/// What the backend knows about a miner's liveness. Pushes and status checks
+/// count towards `consecutive_failures`, any call from the miner is a heartbeat.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct MinerHealth {
+    pub last_push_at: u64,
+    pub last_push_error: Option<String>,
+    pub last_heartbeat: u64,
+    pub cycles: Option<u64>,
+    pub run_status: Option<CanisterRunStatus>,
+    pub last_checked_at: u64,
+    pub last_check_error: Option<String>,
+    pub consecutive_failures: u32,
+}
+
+impl MinerHealth {
+    /// Miners never checked are healthy until a push or check says otherwise.
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_CONSECUTIVE_FAILURES &&
+            self.run_status.map_or(true, |status| status == CanisterRunStatus::Running) &&
+            self.cycles.map_or(true, |cycles| cycles >= MIN_MINER_CYCLES)
+    }
+
+    pub fn push_succeeded(&mut self, now: u64) {
+        self.last_push_at = now;
+        self.last_push_error = None;
+        self.consecutive_failures = 0;
+    }
+
+    pub fn push_failed(&mut self, error: String, now: u64) {
+        self.last_push_at = now;
+        self.last_push_error = Some(error);
+        self.consecutive_failures += 1;
+    }
+
+    pub fn heartbeat(&mut self, now: u64) {
+        self.last_heartbeat = now;
+    }
+
+    /// A successful status check is the way back for a skipped miner: once it
+    /// runs with enough cycles its failures are forgiven.
+    pub fn checked(&mut self, run_status: CanisterRunStatus, cycles: u64, now: u64) {
+        self.run_status = Some(run_status);
+        self.cycles = Some(cycles);
+        self.last_checked_at = now;
+        self.last_check_error = None;
+        if run_status == CanisterRunStatus::Running && cycles >= MIN_MINER_CYCLES {
+            self.consecutive_failures = 0;
+        }
+    }
+
+    pub fn check_failed(&mut self, error: String, now: u64) {
+        self.last_checked_at = now;
+        self.last_check_error = Some(error);
+        self.consecutive_failures += 1;
+    }
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MinerHealthReport {
+    pub miner: Principal,
+    pub healthy: bool,
+    pub health: MinerHealth,
+}
+
+impl MinerHealthReport {
+    pub fn new(miner: Principal, health: MinerHealth) -> Self {
+        Self {
+            miner,
+            healthy: health.is_healthy(),
+            health,
+        }
+    }
+}
@@ -1,18 +1,30 @@
 use candid::{ CandidType, Principal };
+use config::Config;
+use decommission::RetiredMiner;
+use health::MinerHealth;
 use memory::{Block, Transaction};
+use upgrade::{ FleetUpgrade, MinerVersion };
 use serde::{ Deserialize, Serialize };
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{ BTreeMap, BTreeSet };
 
-const COINBASE_REWARDS: u64 = 60_000_000_000;
-const INIT_DIFFICULTY: u32 = 26;
-pub const BLOCK_HALVING: u64 = 17_500;
 pub const SEC_NANOS: u64 = 1_000_000_000;
-pub const BIL_LEDGER_ID: &str = "ktra4-taaaa-aaaag-atveq-cai";
-
+/// Candidates of the current height that still accept solutions.
+pub const MAX_TEMPLATES: usize = 16;
+
+pub mod analytics;
+pub mod config;
+pub mod decommission;
+pub mod emission;
+pub mod governance;
+pub mod health;
 pub mod memory;
 pub mod miner;
+pub mod payment;
+pub mod pool;
+pub mod spawn;
+pub mod upgrade;
 
 #[derive(Debug, Clone)]
 pub struct MinerWasm;
@@ -39,7 +51,8 @@ pub struct State {
 
     pub current_block: Option<Block>,
 
-    pub bil_ledger_id: Principal,
+    #[serde(default)]
+    pub config: Config,
 
     pub miner_to_burned_cycles: BTreeMap<Principal, u64>,
 
@@ -54,12 +67,139 @@ pub struct State {
     pub mempool: Vec<Transaction>,
 
     pub pending_balance: BTreeMap<Principal, u64>,
+
+    #[serde(default)]
+    pub spare_canisters: Vec<Principal>,
+
+    #[serde(default)]
+    pub banned_miners: BTreeSet<Principal>,
+
+    #[serde(default)]
+    pub paused: PauseFlags,
+
+    /// (miner, nonce) of every share accepted for the current block.
+    #[serde(default)]
+    pub block_shares: BTreeSet<(Principal, u128)>,
+
+    /// Time of each miner's last share submission, for rate limiting.
+    #[serde(default)]
+    pub last_share_at: BTreeMap<Principal, u64>,
+
+    /// Candidates issued for the current height by template id; `current_block`
+    /// is the newest one.
+    #[serde(default)]
+    pub templates: BTreeMap<u64, Block>,
+
+    #[serde(default)]
+    pub current_template_id: u64,
+
+    /// Extranonce of every miner ever registered, never reused.
+    #[serde(default)]
+    pub miner_to_extranonce: BTreeMap<Principal, u32>,
+
+    #[serde(default)]
+    pub last_extranonce: u32,
+
+    /// Where a miner's rewards go when its owner set something other than themselves.
+    #[serde(default)]
+    pub miner_to_reward_account: BTreeMap<Principal, Principal>,
+
+    /// Miners paused by their owner; they get no blocks pushed.
+    #[serde(default)]
+    pub paused_miners: BTreeSet<Principal>,
+
+    /// Last spending status each miner reported.
+    #[serde(default)]
+    pub miner_status: BTreeMap<Principal, MinerStatusReport>,
+
+    /// Sha256 of the miner wasm embedded in this build, what new miners get installed.
+    #[serde(default)]
+    pub miner_wasm_hash: Vec<u8>,
+
+    #[serde(default)]
+    pub miner_versions: BTreeMap<Principal, MinerVersion>,
+
+    /// The running or last fleet upgrade.
+    #[serde(default)]
+    pub fleet_upgrade: Option<FleetUpgrade>,
+
+    /// Push results, heartbeats and status checks per miner; unhealthy miners get no blocks.
+    #[serde(default)]
+    pub miner_health: BTreeMap<Principal, MinerHealth>,
+
+    #[serde(default)]
+    pub retired_miners: BTreeMap<Principal, RetiredMiner>,
+
+    /// BIL transferred out by completed withdrawals, counted since this field was added.
+    #[serde(default)]
+    pub total_withdrawn: u64,
+}
+
+/// Nonces reserved for `extranonce`: the top 64 bits of the nonce are the
+/// extranonce, so ranges of different extranonces never overlap.
+pub fn nonce_range(extranonce: u32) -> (u128, u128) {
+    let start = (extranonce as u128) << 64;
+    (start, start + (1 << 64))
+}
+
+/// Work handed to a miner polling `get_block_template`.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BlockTemplate {
+    pub template_id: u64,
+    pub block: Block,
+    /// The miner's extranonce.
+    pub miner_id: u32,
+    /// Leading zero bits a solution needs.
+    pub difficulty: u32,
+    pub share_difficulty: u32,
+    /// Nonces `extranonce_start..extranonce_end` are reserved for this miner.
+    pub extranonce_start: u128,
+    pub extranonce_end: u128,
+}
+
+/// Mining status as reported by a miner, mirroring the miner's own enum.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum MinerStatus {
+    Idle,
+    Mining,
+    Submitting,
+    Paused,
+    OutOfCycles,
+    OverBudget,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MinerStatusReport {
+    pub status: MinerStatus,
+    pub balance: u64,
+    pub updated_at: u64,
+}
+
+/// Circuit breaker: every flag halts one kind of update independently,
+/// queries keep working regardless.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct PauseFlags {
+    pub transactions: bool,
+    pub spawning: bool,
+    pub topups: bool,
+    pub solutions: bool,
+    pub withdrawals: bool,
+    pub deposits: bool,
+    pub block_production: bool,
+    pub reason: Option<String>,
+}
+
+impl PauseFlags {
+    /// Miners have nothing useful to do while blocks are neither produced nor accepted.
+    pub fn mining_halted(&self) -> bool {
+        self.block_production || self.solutions
+    }
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
         Self {
-            current_difficulty: INIT_DIFFICULTY,
+            current_difficulty: config.init_difficulty,
 
             transaction_count: 0,
 
@@ -71,7 +211,7 @@ impl State {
 
             current_block: None,
 
-            bil_ledger_id: Principal::from_text(BIL_LEDGER_ID).unwrap(),
+            config,
 
             miner_to_burned_cycles: BTreeMap::default(),
 
@@ -85,9 +225,41 @@ impl State {
             mempool: Vec::new(),
 
             pending_balance: BTreeMap::default(),
+
+            spare_canisters: Vec::new(),
+
+            banned_miners: BTreeSet::default(),
+
+            paused: PauseFlags::default(),
+
+            block_shares: BTreeSet::default(),
+            last_share_at: BTreeMap::default(),
+            templates: BTreeMap::default(),
+            current_template_id: 0,
+            miner_to_extranonce: BTreeMap::default(),
+            last_extranonce: 0,
+            miner_to_reward_account: BTreeMap::default(),
+            paused_miners: BTreeSet::default(),
+            miner_status: BTreeMap::default(),
+            miner_wasm_hash: Vec::new(),
+            miner_versions: BTreeMap::default(),
+            fleet_upgrade: None,
+            miner_health: BTreeMap::default(),
+            retired_miners: BTreeMap::default(),
+            total_withdrawn: 0,
         }
     }
 
+    /// Mempool transactions the next candidate may include. Withdrawals to
+    /// `backend` stay in the mempool while withdrawals are paused.
+    pub fn block_transactions(&self, backend: Principal) -> Vec<Transaction> {
+        self.mempool
+            .iter()
+            .filter(|tx| !(self.paused.withdrawals && tx.recipient == backend))
+            .cloned()
+            .collect()
+    }
+
     pub fn mined_block_count(&self) -> u64 {
         self.miner_to_mined_block.values().sum()
     }
@@ -96,10 +268,109 @@ impl State {
         self.miner_creation_transactions.insert(block_index);
         self.miner_to_owner.insert(miner, caller);
         self.principal_to_miner.entry(caller).or_default().push(miner);
+        self.assign_extranonce(miner);
+    }
+
+    /// Records a call from `miner`, ignoring callers that are not registered miners.
+    pub fn heartbeat(&mut self, miner: Principal, now: u64) {
+        if self.miner_to_owner.contains_key(&miner) {
+            self.miner_health.entry(miner).or_default().heartbeat(now);
+        }
+    }
+
+    /// Account credited with the miner's rewards: its reward account if set, else its owner.
+    pub fn reward_account(&self, miner: &Principal) -> Option<Principal> {
+        self.miner_to_reward_account
+            .get(miner)
+            .or_else(|| self.miner_to_owner.get(miner))
+            .cloned()
+    }
+
+    /// Moves `miner` to `new_owner`. The reward account is reset to the new owner.
+    pub fn transfer_miner(&mut self, miner: Principal, new_owner: Principal) {
+        if let Some(old_owner) = self.miner_to_owner.insert(miner, new_owner) {
+            if let Some(miners) = self.principal_to_miner.get_mut(&old_owner) {
+                miners.retain(|m| *m != miner);
+            }
+        }
+        self.principal_to_miner.entry(new_owner).or_default().push(miner);
+        self.miner_to_reward_account.remove(&miner);
+    }
+
+    /// Drops `miner` from every registry. Mined blocks, burned cycles and its
+    /// extranonce are kept, the extranonce is never handed out again.
+    pub fn retire_miner(&mut self, miner: Principal, retired: RetiredMiner, now: u64) {
+        if let Some(owner) = self.miner_to_owner.remove(&miner) {
+            if let Some(miners) = self.principal_to_miner.get_mut(&owner) {
+                miners.retain(|m| *m != miner);
+            }
+        }
+        self.miner_to_reward_account.remove(&miner);
+        self.paused_miners.remove(&miner);
+        self.banned_miners.remove(&miner);
+        self.miner_status.remove(&miner);
+        self.miner_health.remove(&miner);
+        self.miner_versions.remove(&miner);
+        self.last_share_at.remove(&miner);
+        if let Some(upgrade) = self.fleet_upgrade.as_mut() {
+            if upgrade.queue.contains(&miner) {
+                upgrade.drop_miner(miner, now);
+            }
+        }
+        self.retired_miners.insert(miner, retired);
+    }
+
+    /// Owner of `miner`, including miners that have been decommissioned.
+    pub fn historical_owner(&self, miner: &Principal) -> Option<Principal> {
+        self.miner_to_owner
+            .get(miner)
+            .or_else(|| self.retired_miners.get(miner).map(|r| &r.owner))
+            .cloned()
+    }
+
+    /// Blocks mined by all miners `owner` has, including decommissioned ones.
+    pub fn owner_block_count(&self, owner: &Principal) -> u64 {
+        let active = self.principal_to_miner.get(owner).into_iter().flatten();
+        let retired = self.retired_miners
+            .iter()
+            .filter(|(_, r)| r.owner == *owner)
+            .map(|(miner, _)| miner);
+        active
+            .chain(retired)
+            .map(|miner| self.miner_to_mined_block.get(miner).cloned().unwrap_or(0))
+            .sum()
+    }
+
+    /// The miner's extranonce, assigning the next unused one on first call.
+    pub fn assign_extranonce(&mut self, miner: Principal) -> u32 {
+        if let Some(extranonce) = self.miner_to_extranonce.get(&miner) {
+            return *extranonce;
+        }
+        self.last_extranonce += 1;
+        self.miner_to_extranonce.insert(miner, self.last_extranonce);
+        self.last_extranonce
+    }
+
+    /// Makes `block` the current candidate and returns its template id. Templates
+    /// of older heights are dropped, as are the oldest beyond `MAX_TEMPLATES`.
+    pub fn issue_template(&mut self, block: Block) -> u64 {
+        self.templates.retain(|_, t| t.header.height == block.header.height);
+        self.current_template_id += 1;
+        self.templates.insert(self.current_template_id, block.clone());
+        while self.templates.len() > MAX_TEMPLATES {
+            self.templates.pop_first();
+        }
+        self.current_block = Some(block);
+        self.current_template_id
     }
 
+    pub fn current_template(&self) -> Option<(Block, u64)> {
+        self.current_block.clone().map(|block| (block, self.current_template_id))
+    }
+
+    /// Reward of the latest block.
     pub fn current_rewards(&self) -> u64 {
-        COINBASE_REWARDS >> (self.mined_block_count() / BLOCK_HALVING)
+        emission::reward_at(&self.config, self.block_height)
     }
 }
 
@@ -115,4 +386,85 @@ pub fn replace_state(state: State) {
     __STATE.with(|s| {
         *s.borrow_mut() = Some(state);
     });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn miner(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn nonces_overlap(a: u32, b: u32) -> bool {
+        let (a_start, a_end) = nonce_range(a);
+        let (b_start, b_end) = nonce_range(b);
+        a_start < b_end && b_start < a_end
+    }
+
+    #[test]
+    fn extranonces_are_unique_and_stable() {
+        let mut state = State::new(Config::default());
+        let owner = miner(100);
+        let assigned: Vec<u32> = (1..=5)
+            .map(|id| {
+                state.new_miner(miner(id), owner, id as u64);
+                state.assign_extranonce(miner(id))
+            })
+            .collect();
+
+        for (i, a) in assigned.iter().enumerate() {
+            for b in &assigned[i + 1..] {
+                assert_ne!(a, b);
+                assert!(!nonces_overlap(*a, *b));
+            }
+        }
+        // Asking again, or registering again, keeps the miner's extranonce.
+        for id in 1..=5 {
+            assert_eq!(state.assign_extranonce(miner(id)), assigned[(id - 1) as usize]);
+        }
+        state.new_miner(miner(1), owner, 99);
+        assert_eq!(state.assign_extranonce(miner(1)), assigned[0]);
+    }
+
+    #[test]
+    fn retired_extranonces_are_not_reused() {
+        let mut state = State::new(Config::default());
+        let owner = miner(100);
+        state.new_miner(miner(1), owner, 1);
+        state.new_miner(miner(2), owner, 2);
+        let retired = state.assign_extranonce(miner(2));
+
+        state.retire_miner(
+            miner(2),
+            RetiredMiner {
+                owner,
+                cycles_refunded: 0,
+                cycles_to: owner,
+                retired_at: 0,
+            },
+            0
+        );
+        state.new_miner(miner(3), owner, 3);
+
+        let assigned = state.assign_extranonce(miner(3));
+        assert_ne!(assigned, retired);
+        assert_ne!(assigned, state.assign_extranonce(miner(1)));
+        assert!(!nonces_overlap(assigned, retired));
+    }
+
+    #[test]
+    fn extranonces_survive_a_state_round_trip() {
+        let mut state = State::new(Config::default());
+        state.new_miner(miner(1), miner(100), 1);
+        state.new_miner(miner(2), miner(100), 2);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&state, &mut bytes).unwrap();
+        let mut restored: State = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+
+        assert_eq!(restored.miner_to_extranonce, state.miner_to_extranonce);
+        let next = restored.assign_extranonce(miner(3));
+        assert!(!state.miner_to_extranonce.values().any(|e| *e == next));
+    }
+}
@@ -1,61 +1,166 @@
-use std::hash::Hasher;
-use rapidhash::RapidHasher;
 use windoge_pow_backend::memory::{
     add_balance,
     add_state,
     all_blocks,
+    all_roles,
     all_stats,
+    audit_entries,
+    audit_entry_count,
     block_count,
     get_balance,
     get_last_state,
+    get_role,
+    get_spawn,
+    get_wasm,
     get_stat,
+    has_wasm,
     insert_block,
     insert_new_miner,
     insert_new_transaction,
+    insert_share,
+    insert_spawn,
     insert_stats,
+    insert_wasm,
     latest_block,
     miner_count,
+    pending_spawns,
+    remove_miner,
+    remove_role,
+    set_miner_owner,
+    set_role,
+    spawns_of,
     sub_balance,
+    total_balance,
+    total_deposited,
     transaction_count,
+    wasm_versions,
+    window_shares,
     Block,
     Stats,
     Transaction,
     TransactionArgs,
 };
-use windoge_pow_backend::miner::{ create_canister, install_code };
+use windoge_pow_backend::analytics::{
+    block_points,
+    block_time_summary,
+    blocks_per_owner,
+    difficulty_history,
+    hashrate_history,
+    height_at,
+    BlockPoint,
+    BlockTimeSummary,
+    DifficultyPoint,
+    HashratePoint,
+};
+use windoge_pow_backend::config::{
+    percent_of,
+    Config,
+    DistributionMode,
+    EmptyBlockPolicy,
+    InitArgs,
+    UpgradeArgs,
+};
+use windoge_pow_backend::emission::{
+    emission_curve,
+    emitted_until,
+    epoch_at,
+    final_epoch,
+    max_supply,
+    reward_at,
+    reward_migration_report,
+    EmissionEpoch,
+    SupplyInfo,
+};
+use windoge_pow_backend::decommission::{ DecommissionGuard, RetiredMiner };
+use windoge_pow_backend::governance::{ audit, authorize, is_controller, role_of, AuditEntry, Role };
+use windoge_pow_backend::health::{ CanisterRunStatus, MinerHealthReport };
+use windoge_pow_backend::miner::{
+    canister_status,
+    create_canister,
+    delete_canister,
+    reinstall_code,
+    stop_canister,
+    upgrade_code,
+    MinerCanisterStatus,
+};
+use windoge_pow_backend::payment::{
+    claim_deposit,
+    nat_to_u64,
+    verify_payment,
+    Account,
+    IcLedger,
+    LedgerApi,
+    PaymentKind,
+    PaymentRules,
+};
+use windoge_pow_backend::pool::{
+    estimate_hashrates,
+    split_reward,
+    Share,
+    HASHRATE_WINDOW,
+    MAX_SHARES_PER_SUBMIT,
+};
+use windoge_pow_backend::spawn::{ PaymentSource, SpawnGuard, SpawnRecord, SpawnStep };
+use windoge_pow_backend::upgrade::{
+    hash_to_hex,
+    rollback_queue,
+    upgrade_queue,
+    wasm_hash,
+    FleetUpgrade,
+    FleetUpgradeGuard,
+    FleetUpgradeKind,
+    FleetUpgradeState,
+    MinerVersion,
+    UpgradeStatus,
+    WasmVersion,
+    UPGRADE_BATCH_SIZE,
+};
 use windoge_pow_backend::{
     miner_wasm,
     mutate_state,
+    nonce_range,
     read_state,
     replace_state,
+    BlockTemplate,
+    MinerStatus,
+    MinerStatusReport,
+    PauseFlags,
     State,
-    BIL_LEDGER_ID,
-    BLOCK_HALVING,
     SEC_NANOS,
 };
 use candid::{ CandidType, Decode, Encode, Principal };
 use ic_cdk::{ init, post_upgrade, pre_upgrade, query, update };
 
-const WINDOGE_LEDGER_ID: &str = "rh2pm-ryaaa-aaaan-qeniq-cai";
 const WINDOGE_RECEIVER: &str = "zp2fk-qfdts-3jpq4-oe2lv-xphrr-akxnj-dgtwc-f2psp-wsomh-e5gyz-aae";
-const WINDOGE_MINER_CREATION_AMOUNT: u64 = 1500000000; // 15 Windoge98
-const BLOCK_TIME: u64 = 300 * SEC_NANOS; // 5 minutes
-const MAX_DIFFICULTY: u32 = 48;
-const MIN_DIFFICULTY: u32 = 24;
-const TRANSACTION_LIMIT: usize = 150;
-const BLOCK_BATCH_SIZE: usize = 100;
+const MAX_PAYMENT_AGE: u64 = 7 * 24 * 60 * 60 * SEC_NANOS; // 7 days
+const MAX_PAGE_SIZE: u64 = 1_000;
+const SPAWN_RECOVERY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+const TEMPLATE_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1800);
 
 fn main() {}
 
 #[init]
-fn init() {
-    let state = State::new();
+fn init(args: Option<InitArgs>) {
+    let mut config = Config::default();
+    if let Some(args) = args {
+        config.apply(args);
+    }
+    if let Err(e) = config.validate() {
+        ic_cdk::trap(&format!("invalid init args: {}", e));
+    }
+
+    let state = State::new(config);
     replace_state(state);
 
     let block = Block::genesis();
     let _ = insert_block(block);
 
+    register_miner_wasm();
     start_next_block(1);
+    start_spawn_recovery();
+    start_template_refresh();
+    start_health_checks();
 }
 
 #[pre_upgrade]
@@ -65,16 +170,65 @@ fn pre_upgrade() {
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<UpgradeArgs>) {
     if let Some(old_state) = get_last_state() {
         replace_state(old_state);
     }
 
+    if let Some(args) = args {
+        let mut config = read_state(|s| s.config.clone());
+        config.apply(args.clone());
+        if let Err(e) = config.validate() {
+            ic_cdk::trap(&format!("invalid upgrade args: {}", e));
+        }
+        mutate_state(|s| {
+            s.config = config;
+        });
+        audit(ic_cdk::caller(), "upgrade_config", format!("{:?}", args));
+    }
+
+    // Miners registered before extranonces existed get theirs now.
+    mutate_state(|s| {
+        let miners: Vec<Principal> = s.miner_to_owner.keys().cloned().collect();
+        for miner in miners {
+            s.assign_extranonce(miner);
+        }
+    });
+
+    // States saved before templates existed still have a block out to the miners.
     mutate_state(|s| {
-        s.bil_ledger_id = Principal::from_text(BIL_LEDGER_ID).unwrap();
+        if s.templates.is_empty() {
+            if let Some(block) = s.current_block.clone() {
+                s.issue_template(block);
+            }
+        }
     });
 
+    if let Some(report) = read_state(|s| reward_migration_report(s, block_count() - 1)) {
+        audit(ic_cdk::caller(), "reward_height_mismatch", report);
+    }
+
+    register_miner_wasm();
+    // A fleet upgrade interrupted by this upgrade continues with its remaining queue.
+    if read_state(|s| s.fleet_upgrade.as_ref().is_some_and(|u| u.is_running())) {
+        schedule_fleet_upgrade();
+    }
+
     start_next_block(1);
+    start_spawn_recovery();
+    start_template_refresh();
+    start_health_checks();
+}
+
+/// Stores the embedded miner wasm by hash, so fleets can still roll back to it
+/// after a later build embeds another one.
+fn register_miner_wasm() {
+    let wasm = miner_wasm();
+    let hash = wasm_hash(&wasm);
+    insert_wasm(hash.clone(), wasm.to_vec(), ic_cdk::api::time());
+    mutate_state(|s| {
+        s.miner_wasm_hash = hash;
+    });
 }
 
 #[query]
@@ -97,6 +251,11 @@ fn get_stats(index: u64) -> Option<Stats> {
     get_stat(index)
 }
 
+#[query]
+fn get_config() -> Config {
+    read_state(|s| s.config.clone())
+}
+
 #[query]
 fn get_difficulty() -> u32 {
     read_state(|s| s.current_difficulty)
@@ -109,11 +268,42 @@ fn get_current_rewards() -> u64 {
 
 #[query]
 fn get_next_halving() -> u64 {
-    let mined_blocks = read_state(|s| s.mined_block_count());
-    let blocks_to_next_halving = BLOCK_HALVING - (mined_blocks % BLOCK_HALVING);
+    let block_height = read_state(|s| s.block_height);
+    let block_halving = read_state(|s| s.config.block_halving);
+    let blocks_to_next_halving = block_halving - (block_height % block_halving);
     blocks_to_next_halving
 }
 
+#[query]
+fn get_supply_info() -> SupplyInfo {
+    let (config, block_height, current_reward, total_withdrawn) = read_state(|s| (
+        s.config.clone(),
+        s.block_height,
+        s.current_rewards(),
+        s.total_withdrawn,
+    ));
+    let total_minted = emitted_until(&config, block_height);
+    let total_deposited = total_deposited();
+    let circulating_supply = total_balance();
+
+    SupplyInfo {
+        total_minted,
+        total_deposited,
+        total_withdrawn,
+        circulating_supply,
+        max_supply: max_supply(&config),
+        current_reward,
+        current_epoch: epoch_at(&config, block_height),
+        final_epoch: final_epoch(&config),
+    }
+}
+
+/// Epochs `start..start + length` of the emission schedule.
+#[query]
+fn get_emission_curve(start: u64, length: u64) -> Vec<EmissionEpoch> {
+    read_state(|s| emission_curve(&s.config, start, std::cmp::min(length, MAX_PAGE_SIZE)))
+}
+
 #[query]
 fn get_mempool() -> Vec<Transaction> {
     read_state(|s| s.mempool.clone())
@@ -150,62 +340,136 @@ async fn topup_miner(miner: Principal, block_index: u64) -> Result<String, Strin
         return Err("caller is anonymous".to_string());
     }
 
-    if let Some(_) = read_state(|s| s.miner_to_owner.get(&miner).cloned()) {
-        if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
-            return Err("transaction already processed".to_string());
-        }
+    if read_state(|s| s.paused.topups) {
+        return Err("top-ups are paused".to_string());
+    }
 
-        let index = candid::Nat::from(block_index);
-        let transaction = fetch_block(index).await?;
+    if !read_state(|s| s.miner_to_owner.contains_key(&miner)) {
+        return Err("miner not found".to_string());
+    }
 
-        if let Some(transfer) = transaction.transfer {
-            if transfer.from.owner != ic_cdk::caller() {
-                return Err("transfer not from caller".to_string());
-            }
-            if transfer.to.owner != ic_cdk::id() {
-                return Err("transfer not to BIL canister".to_string());
-            }
+    if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
+        return Err("transaction already processed".to_string());
+    }
 
-            let burn_amount = nat_to_u64((transfer.amount.clone() * 10_u128) / 100_u128)?;
-            match
-                burn_exe(BurnArgs {
-                    memo: None,
-                    from_subaccount: None,
-                    created_at_time: None,
-                    amount: candid::Nat::from(burn_amount),
-                }).await
-            {
-                Ok(index) => {
-                    ic_cdk::println!("Burned {} EXE, index: {:?}", burn_amount, index);
-                    mutate_state(|s| {
-                        s.exe_burned += burn_amount;
-                    });
-                }
-                Err(e) => ic_cdk::println!("Error burning {} EXE: {:?}", burn_amount, e),
-            }
+    let rules = PaymentRules {
+        from: Some(ic_cdk::caller()),
+        max_age: Some(MAX_PAYMENT_AGE),
+        ..PaymentRules::transfer_to(ic_cdk::id())
+    };
+    let payment = verify_payment(&windoge_ledger(), block_index, &rules, ic_cdk::api::time()).await?;
 
-            let cycles_amount = tokens_to_cycles(nat_to_u64(transfer.amount)?);
+    if !mutate_state(|s| s.miner_creation_transactions.insert(block_index)) {
+        return Err("transaction already processed".to_string());
+    }
 
-            match transfer_cycles(miner, (cycles_amount * 80) / 100).await {
-                Ok(_) => {
-                    let _ = insert_new_transaction(block_index);
-                    mutate_state(|s| {
-                        s.miner_creation_transactions.insert(block_index);
-                    });
-                    ic_cdk::println!("Topped up miner {}", miner.to_text());
-                    return Ok("topped up miner".to_string());
-                }
-                Err(e) => {
-                    ic_cdk::println!("Error topping up miner: {:?}", e);
-                    return Err("error topping up miner".to_string());
-                }
-            }
-        } else {
-            return Err("expected transfer".to_string());
+    match apply_topup(miner, payment.amount).await {
+        Ok(_) => {
+            let _ = insert_new_transaction(block_index);
+            Ok("topped up miner".to_string())
         }
-    } else {
+        Err(e) => {
+            mutate_state(|s| {
+                s.miner_creation_transactions.remove(&block_index);
+            });
+            Err(e)
+        }
+    }
+}
+
+#[update]
+async fn topup_miner_with_approval(miner: Principal, amount: u64) -> Result<String, String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+
+    if read_state(|s| s.paused.topups) {
+        return Err("top-ups are paused".to_string());
+    }
+
+    if !read_state(|s| s.miner_to_owner.contains_key(&miner)) {
         return Err("miner not found".to_string());
     }
+
+    if amount == 0 {
+        return Err("amount must be greater than 0".to_string());
+    }
+
+    let caller = ic_cdk::caller();
+    let block_index = pull_payment(caller, amount).await?;
+
+    match apply_topup(miner, amount).await {
+        Ok(_) => {
+            let _ = insert_new_transaction(block_index);
+            Ok("topped up miner".to_string())
+        }
+        Err(e) => Err(refund_payment(caller, amount, e).await),
+    }
+}
+
+/// Sends 80% of the payment's worth in cycles to `miner` and burns 10% of the payment.
+async fn apply_topup(miner: Principal, amount: u64) -> Result<(), String> {
+    let config = read_state(|s| s.config.clone());
+    let cycles_amount = percent_of(tokens_to_cycles(amount), config.topup_cycles_percent);
+
+    if let Err(e) = transfer_cycles(miner, cycles_amount).await {
+        ic_cdk::println!("Error topping up miner: {:?}", e);
+        return Err("error topping up miner".to_string());
+    }
+
+    ic_cdk::println!("Topped up miner {}", miner.to_text());
+
+    let burn_amount = percent_of(amount, config.topup_burn_percent);
+    match
+        burn_exe(BurnArgs {
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount: candid::Nat::from(burn_amount),
+        }).await
+    {
+        Ok(index) => {
+            ic_cdk::println!("Burned {} EXE, index: {:?}", burn_amount, index);
+            mutate_state(|s| {
+                s.exe_burned += burn_amount;
+            });
+        }
+        Err(e) => ic_cdk::println!("Error burning {} EXE: {:?}", burn_amount, e),
+    }
+
+    Ok(())
+}
+
+#[update]
+async fn deposit(block_index: u64) -> Result<u64, String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
+    }
+
+    if read_state(|s| s.paused.deposits) {
+        return Err("deposits are paused".to_string());
+    }
+
+    // The backend is the minting account of the BIL ledger, so a transfer
+    // to it is recorded by the ledger as a burn.
+    let rules = PaymentRules {
+        kinds: vec![PaymentKind::Transfer, PaymentKind::Burn],
+        from: Some(ic_cdk::caller()),
+        ..PaymentRules::transfer_to(ic_cdk::id())
+    };
+    let bil_ledger = IcLedger::new(read_state(|s| s.config.bil_ledger_id), LedgerApi::GetTransaction);
+    let amount = claim_deposit(
+        &bil_ledger,
+        block_index,
+        &rules,
+        ic_cdk::caller(),
+        ic_cdk::api::time()
+    ).await?;
+    add_balance(ic_cdk::caller(), amount);
+
+    ic_cdk::println!("Deposited {} BIL for {}", amount, ic_cdk::caller().to_text());
+
+    Ok(amount)
 }
 
 #[update]
@@ -214,6 +478,14 @@ async fn create_transaction(transaction_arg: TransactionArgs) -> Result<String,
         return Err("caller is anonymous".to_string());
     }
 
+    if read_state(|s| s.paused.transactions) {
+        return Err("transactions are paused".to_string());
+    }
+
+    if transaction_arg.recipient == ic_cdk::id() && read_state(|s| s.paused.withdrawals) {
+        return Err("withdrawals are paused".to_string());
+    }
+
     let pending_amount = read_state(|s|
         s.pending_balance.get(&ic_cdk::caller()).cloned().unwrap_or(0)
     );
@@ -223,7 +495,7 @@ async fn create_transaction(transaction_arg: TransactionArgs) -> Result<String,
         return Err("insufficient balance".to_string());
     }
 
-    if read_state(|s| s.mempool.len()) > TRANSACTION_LIMIT {
+    if read_state(|s| (s.mempool.len() as u64) > s.config.transaction_limit) {
         return Err("network is congested, transactions can be processed in next block".to_string());
     }
 
@@ -260,185 +532,515 @@ async fn spawn_miner(block_index: u64) -> Result<Principal, String> {
         return Err("caller is anonymous".to_string());
     }
 
-    if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
-        return Err("transaction already processed".to_string());
+    if read_state(|s| s.paused.spawning) {
+        return Err("spawning is paused".to_string());
     }
 
-    let index = candid::Nat::from(block_index);
-    let transaction = fetch_block(index).await?;
-
-    if let Some(transfer) = transaction.transfer {
-        if transfer.from.owner != ic_cdk::caller() {
-            return Err("transfer not from caller".to_string());
-        }
-        if transfer.amount < WINDOGE_MINER_CREATION_AMOUNT {
-            return Err("transfer amount too low".to_string());
-        }
-        if transfer.to.owner != ic_cdk::id() {
-            return Err("transfer not to BIL canister".to_string());
+    if let Some(record) = get_spawn(block_index) {
+        if record.owner != ic_cdk::caller() {
+            return Err("transaction already processed".to_string());
         }
-    } else {
-        return Err("expected transfer".to_string());
+        return run_spawn(block_index).await;
     }
 
-    let arg = Encode!(&ic_cdk::caller()).unwrap();
+    if read_state(|s| s.miner_creation_transactions.contains(&block_index)) {
+        return Err("transaction already processed".to_string());
+    }
 
-    let canister_id = create_canister(2_500_000_000_000).await.map_err(|e|
-        format!("{} - {:?}", e.method, e.reason)
-    )?;
+    let rules = PaymentRules {
+        from: Some(ic_cdk::caller()),
+        min_amount: read_state(|s| s.config.miner_creation_amount),
+        max_age: Some(MAX_PAYMENT_AGE),
+        ..PaymentRules::transfer_to(ic_cdk::id())
+    };
+    let payment = verify_payment(&windoge_ledger(), block_index, &rules, ic_cdk::api::time()).await?;
 
-    install_code(canister_id, miner_wasm().to_vec(), arg).await.map_err(|e|
-        format!("{} - {:?}", e.method, e.reason)
-    )?;
+    if !mutate_state(|s| s.miner_creation_transactions.insert(block_index)) {
+        return Err("transaction already processed".to_string());
+    }
+    insert_spawn(
+        SpawnRecord::new(
+            block_index,
+            ic_cdk::caller(),
+            payment.amount,
+            PaymentSource::Transfer,
+            ic_cdk::api::time()
+        )
+    );
 
-    mutate_state(|s| {
-        s.new_miner(canister_id, ic_cdk::caller(), block_index);
-    });
-    insert_new_miner(canister_id, ic_cdk::caller(), block_index);
-    let _ = insert_new_transaction(block_index);
+    run_spawn(block_index).await
+}
 
-    match
-        burn_exe(BurnArgs {
-            memo: None,
-            from_subaccount: None,
-            created_at_time: None,
-            amount: ((WINDOGE_MINER_CREATION_AMOUNT * 40) / 100).into(),
-        }).await
-    {
-        Ok(index) => {
-            ic_cdk::println!(
-                "Burned {} EXE, index: {:?}",
-                (WINDOGE_MINER_CREATION_AMOUNT * 40) / 100,
-                index
-            );
-            mutate_state(|s| {
-                s.exe_burned += (WINDOGE_MINER_CREATION_AMOUNT * 40) / 100;
-            });
-        }
-        Err(e) => ic_cdk::println!("Error burning EXE: {:?}", e),
+#[update]
+async fn spawn_miner_with_approval() -> Result<Principal, String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("caller is anonymous".to_string());
     }
 
-    if let Some(block) = read_state(|s| s.current_block.clone()) {
-        ic_cdk::spawn(async move {
-            let _: Result<(), _> = ic_cdk::api::call::call(canister_id, "push_block", (
-                block,
-                2_u32,
-            )).await;
-        });
+    if read_state(|s| s.paused.spawning) {
+        return Err("spawning is paused".to_string());
     }
 
-    ic_cdk::println!("Miner {} spawned", canister_id.to_text());
+    let caller = ic_cdk::caller();
+    let amount = read_state(|s| s.config.miner_creation_amount);
+    let block_index = pull_payment(caller, amount).await?;
+    insert_spawn(
+        SpawnRecord::new(
+            block_index,
+            caller,
+            amount,
+            PaymentSource::Approval,
+            ic_cdk::api::time()
+        )
+    );
 
-    Ok(canister_id)
+    run_spawn(block_index).await
 }
 
-#[update(hidden = true)]
-async fn submit_solution(block: Block, stats: Stats) -> Result<bool, String> {
-    if let Err(e) = validate_solution(&block) {
-        ic_cdk::println!("Solution from miner {} rejected: {}", ic_cdk::caller().to_text(), e);
-        return Err(e);
+/// Resumes a pending spawn. Callable by the spawn's owner or a controller.
+#[update]
+async fn retry_spawn(block_index: u64) -> Result<Principal, String> {
+    let record = get_spawn(block_index).ok_or_else(|| "spawn not found".to_string())?;
+    if record.owner != ic_cdk::caller() && !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("caller is not allowed".to_string());
     }
 
-    mutate_state(|s| {
-        s.miner_to_mined_block
-            .entry(ic_cdk::caller())
-            .and_modify(|e| {
-                *e += 1;
-            })
-            .or_insert(1);
+    run_spawn(block_index).await
+}
 
-        s.miner_to_burned_cycles
-            .entry(ic_cdk::caller())
-            .and_modify(|e| {
-                *e += stats.cycles_burned;
-            })
-            .or_insert(stats.cycles_burned);
+#[query]
+fn get_spawn_status(block_index: u64) -> Option<SpawnRecord> {
+    get_spawn(block_index)
+}
 
-        s.average_block_time =
-            (s.average_block_time * (block.header.height - 1) + stats.solve_time) /
-            block.header.height;
+#[query]
+fn get_spawns(owner: Principal) -> Vec<SpawnRecord> {
+    spawns_of(owner)
+}
 
-        s.block_height = block.header.height;
-        s.transaction_count += block.transactions.len() as u64;
+/// Drives a spawn through its remaining steps. Every step is persisted before the
+/// next one starts, so a failed or interrupted run can be resumed where it stopped.
+async fn run_spawn(block_index: u64) -> Result<Principal, String> {
+    if read_state(|s| s.paused.spawning) {
+        return Err("spawning is paused".to_string());
+    }
 
-        for tx in &block.transactions {
-            if let Some(pos) = s.mempool.iter().position(|x| x == tx) {
-                s.mempool.remove(pos);
+    let _guard = SpawnGuard::new(block_index)?;
+
+    loop {
+        let mut record = get_spawn(block_index).ok_or_else(|| "spawn not found".to_string())?;
+        let now = ic_cdk::api::time();
+
+        match record.step {
+            SpawnStep::PaymentVerified => {
+                let spare = mutate_state(|s| s.spare_canisters.pop());
+                let canister_id = match spare {
+                    Some(canister_id) => canister_id,
+                    None =>
+                        match create_canister(read_state(|s| s.config.miner_creation_cycles)).await {
+                            Ok(canister_id) => canister_id,
+                            Err(e) => {
+                                return Err(fail_spawn(record, format!("{} - {:?}", e.method, e.reason)));
+                            }
+                        }
+                };
+                record.canister_id = Some(canister_id);
+                record.advance(SpawnStep::CanisterCreated, now);
             }
-
-            if let Some(_) = s.pending_balance.get(&tx.sender) {
-                s.pending_balance.entry(tx.sender).and_modify(|e| {
-                    *e -= tx.amount;
+            SpawnStep::CanisterCreated => {
+                let canister_id = record.canister_id.expect("created canister is recorded");
+                let arg = Encode!(&record.owner).unwrap();
+                // Reinstall behaves like install on an empty canister and keeps retries and
+                // reused spare canisters idempotent.
+                if let Err(e) = reinstall_code(canister_id, miner_wasm().to_vec(), arg).await {
+                    return Err(fail_spawn(record, format!("{} - {:?}", e.method, e.reason)));
+                }
+                record.advance(SpawnStep::CodeInstalled, now);
+            }
+            SpawnStep::CodeInstalled => {
+                let canister_id = record.canister_id.expect("created canister is recorded");
+                mutate_state(|s| {
+                    s.new_miner(canister_id, record.owner, block_index);
+                    let version = MinerVersion::installed(s.miner_wasm_hash.clone(), now);
+                    s.miner_versions.insert(canister_id, version);
                 });
+                insert_new_miner(canister_id, record.owner, block_index);
+                let _ = insert_new_transaction(block_index);
+                record.advance(SpawnStep::Registered, now);
             }
-        }
-    });
-
-    let _ = insert_block(block.clone());
-    let _ = insert_stats(stats.clone());
-
-    for transaction in block.transactions {
-        if transaction.recipient == ic_cdk::id() {
-            let transfer = TransferArg {
-                to: Account {
-                    owner: transaction.sender,
-                    subaccount: None,
-                },
-                fee: None,
-                from_subaccount: None,
-                memo: None,
-                created_at_time: None,
-                amount: transaction.amount.into(),
-            };
-            ic_cdk::spawn(async move {
-                match icrc1_transfer(transfer, Principal::from_text(BIL_LEDGER_ID).unwrap()).await {
-                    Ok(_) => {
-                        ic_cdk::println!("BIL minted successfully");
-                        sub_balance(transaction.sender, transaction.amount);
-                    }
-                    Err(e) => {
-                        ic_cdk::println!("Error minting BIL: {:?}", e);
-                    }
+            SpawnStep::Registered => {
+                // The miner is usable already; a failed burn is retried by the recovery timer.
+                if let Err(e) = burn_spawn_fee().await {
+                    return Err(fail_spawn(record, e));
                 }
-            });
-        } else {
-            add_balance(transaction.recipient, transaction.amount);
-            sub_balance(transaction.sender, transaction.amount);
+                record.advance(SpawnStep::Burned, now);
+            }
+            SpawnStep::Burned => {
+                let canister_id = record.canister_id.expect("created canister is recorded");
+                finish_spawn(canister_id);
+                record.advance(SpawnStep::Completed, now);
+            }
+            SpawnStep::Completed => {
+                return Ok(record.canister_id.expect("created canister is recorded"));
+            }
+            SpawnStep::Failed => {
+                return Err(compensate_spawn(record).await);
+            }
+            SpawnStep::Refunded => {
+                return Err(
+                    format!(
+                        "spawn failed and payment was refunded: {}",
+                        record.last_error.unwrap_or_default()
+                    )
+                );
+            }
         }
+
+        insert_spawn(record);
     }
+}
 
-    let miner_owner = read_state(|s|
-        s.miner_to_owner.get(&ic_cdk::caller()).cloned().unwrap_or(Principal::anonymous())
-    );
-    add_balance(
-        miner_owner,
-        read_state(|s| s.current_rewards())
+fn fail_spawn(mut record: SpawnRecord, error: String) -> String {
+    ic_cdk::println!("Spawn {} failed at {:?}: {}", record.block_index, record.step, error);
+    record.fail(error.clone(), ic_cdk::api::time());
+    let retry = if record.step == SpawnStep::Failed {
+        "payment will be refunded"
+    } else {
+        "spawn will be retried"
+    };
+    insert_spawn(record);
+    format!("{}, {}", error, retry)
+}
+
+/// Burns the share of the creation fee `spawn_burn_percent` asks for. The step is
+/// recorded in the same message that sees the burn succeed, so it never runs twice.
+async fn burn_spawn_fee() -> Result<(), String> {
+    let burn_amount = read_state(|s|
+        percent_of(s.config.miner_creation_amount, s.config.spawn_burn_percent)
     );
+    let index = burn_exe(BurnArgs {
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+        amount: burn_amount.into(),
+    }).await.map_err(|e| format!("error burning EXE: {}", e))?;
 
-    if BLOCK_TIME > stats.solve_time {
-        let sec = (BLOCK_TIME - stats.solve_time) / SEC_NANOS;
-        if sec > 60 && read_state(|s| s.current_difficulty) < MAX_DIFFICULTY {
-            mutate_state(|s| {
-                s.current_difficulty = s.current_difficulty + 1;
-            });
-        }
-    } else {
-        let sec = (stats.solve_time - BLOCK_TIME) / SEC_NANOS;
-        if sec > 60 && read_state(|s| s.current_difficulty) > MIN_DIFFICULTY {
-            mutate_state(|s| {
-                s.current_difficulty = s.current_difficulty - 1;
-            });
+    ic_cdk::println!("Burned {} EXE, index: {:?}", burn_amount, index);
+    mutate_state(|s| {
+        s.exe_burned += burn_amount;
+    });
+    Ok(())
+}
+
+/// Hands the current block to the freshly spawned miner.
+fn finish_spawn(canister_id: Principal) {
+    // In pull mode the new miner picks up work on its first poll.
+    if read_state(|s| s.config.distribution_mode) == DistributionMode::Push {
+        if let Some((block, template_id)) = read_state(|s| s.current_template()) {
+            push_block(block, template_id, canister_id);
         }
     }
 
-    ic_cdk::println!("Solution from miner {} accepted", ic_cdk::caller().to_text());
-
+    ic_cdk::println!("Miner {} spawned", canister_id.to_text());
+}
+
+/// Refunds an abandoned spawn and keeps its canister, if any, for the next spawn.
+async fn compensate_spawn(mut record: SpawnRecord) -> String {
+    let error = record.last_error.clone().unwrap_or_default();
+
+    match refund(record.owner, record.amount).await {
+        Ok(_) => {
+            if let Some(canister_id) = record.canister_id.take() {
+                mutate_state(|s| s.spare_canisters.push(canister_id));
+            }
+            record.step = SpawnStep::Refunded;
+            record.updated_at = ic_cdk::api::time();
+            insert_spawn(record);
+            format!("{}, payment refunded", error)
+        }
+        Err(e) => {
+            record.updated_at = ic_cdk::api::time();
+            insert_spawn(record);
+            format!("{}, refund failed: {}", error, e)
+        }
+    }
+}
+
+fn start_spawn_recovery() {
+    ic_cdk_timers::set_timer_interval(SPAWN_RECOVERY_INTERVAL, || {
+        ic_cdk::spawn(recover_spawns());
+    });
+}
+
+fn start_health_checks() {
+    ic_cdk_timers::set_timer_interval(HEALTH_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "check_miner_health", (
+                0 as u64,
+            )).await;
+        });
+    });
+}
+
+fn start_template_refresh() {
+    ic_cdk_timers::set_timer_interval(TEMPLATE_REFRESH_INTERVAL, || {
+        refresh_template();
+    });
+}
+
+/// Rebuilds the current candidate when transactions arrived since it was issued.
+/// Earlier templates of the same height keep accepting solutions.
+fn refresh_template() {
+    if read_state(|s| s.paused.mining_halted()) {
+        return;
+    }
+
+    let current = match read_state(|s| s.current_block.clone()) {
+        Some(block) => block,
+        None => {
+            return;
+        }
+    };
+    // Already solved, the next `create_block` issues a fresh candidate.
+    if current.header.height != block_count() {
+        return;
+    }
+
+    let transactions = read_state(|s| s.block_transactions(ic_cdk::id()));
+    if transactions == current.transactions {
+        return;
+    }
+
+    let prev_block = latest_block().unwrap();
+    match Block::new(&prev_block, transactions, current.header.difficulty) {
+        Ok(block) => {
+            let template_id = mutate_state(|s| s.issue_template(block.clone()));
+            ic_cdk::println!(
+                "Refreshed template {} with {} transactions",
+                template_id,
+                block.transactions.len()
+            );
+            distribute_template(block, template_id);
+        }
+        Err(e) => {
+            ic_cdk::println!("Error refreshing template: {:?}", e);
+        }
+    }
+}
+
+async fn recover_spawns() {
+    for record in pending_spawns() {
+        if let Err(e) = run_spawn(record.block_index).await {
+            ic_cdk::println!("Spawn {} not recovered: {}", record.block_index, e);
+        }
+    }
+}
+
+/// Pulls `amount` Windoge98 from `from` via ICRC-2 and returns the ledger block index.
+/// The index is marked as processed so it cannot be claimed again through `spawn_miner`.
+async fn pull_payment(from: Principal, amount: u64) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+        amount: candid::Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let index = icrc2_transfer_from(args, windoge_ledger().canister_id).await?;
+    let block_index = nat_to_u64(index)?;
+
+    mutate_state(|s| {
+        s.miner_creation_transactions.insert(block_index);
+    });
+
+    Ok(block_index)
+}
+
+/// Returns a pulled payment after a failed request and builds the error reported to the caller.
+async fn refund_payment(to: Principal, amount: u64, error: String) -> String {
+    match refund(to, amount).await {
+        Ok(_) => format!("{}, payment refunded", error),
+        Err(e) => format!("{}, refund failed: {}", error, e),
+    }
+}
+
+/// Sends `amount` back to `to`, less the ledger fee the transfer costs.
+async fn refund(to: Principal, amount: u64) -> Result<candid::Nat, String> {
+    let ledger_id = windoge_ledger().canister_id;
+    let fee = icrc1_fee(ledger_id).await?;
+    let amount = amount.checked_sub(fee)
+        .filter(|amount| *amount > 0)
+        .ok_or_else(|| format!("payment of {} does not cover the ledger fee {}", amount, fee))?;
+
+    let transfer = TransferArg {
+        to: Account {
+            owner: to,
+            subaccount: None,
+        },
+        fee: Some(candid::Nat::from(fee)),
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+        amount: candid::Nat::from(amount),
+    };
+
+    match icrc1_transfer(transfer, ledger_id).await {
+        Ok(index) => {
+            ic_cdk::println!("Refunded {} to {}, index: {:?}", amount, to.to_text(), index);
+            Ok(index)
+        }
+        Err(e) => {
+            ic_cdk::println!("Error refunding {} to {}: {:?}", amount, to.to_text(), e);
+            Err(e)
+        }
+    }
+}
+
+#[update(hidden = true)]
+async fn submit_solution(
+    block: Block,
+    stats: Stats,
+    template_id: Option<u64>
+) -> Result<bool, String> {
+    if read_state(|s| s.paused.solutions) {
+        return Err("solutions are paused".to_string());
+    }
+
+    if let Err(e) = validate_solution(&block, template_id) {
+        ic_cdk::println!("Solution from miner {} rejected: {}", ic_cdk::caller().to_text(), e);
+        return Err(e);
+    }
+
+    mutate_state(|s| {
+        s.heartbeat(ic_cdk::caller(), ic_cdk::api::time());
+
+        s.miner_to_mined_block
+            .entry(ic_cdk::caller())
+            .and_modify(|e| {
+                *e += 1;
+            })
+            .or_insert(1);
+
+        s.miner_to_burned_cycles
+            .entry(ic_cdk::caller())
+            .and_modify(|e| {
+                *e += stats.cycles_burned;
+            })
+            .or_insert(stats.cycles_burned);
+
+        s.average_block_time =
+            (s.average_block_time * (block.header.height - 1) + stats.solve_time) /
+            block.header.height;
+
+        s.block_height = block.header.height;
+        s.transaction_count += block.transactions.len() as u64;
+
+        for tx in &block.transactions {
+            if let Some(pos) = s.mempool.iter().position(|x| x == tx) {
+                s.mempool.remove(pos);
+            }
+
+            if let Some(_) = s.pending_balance.get(&tx.sender) {
+                s.pending_balance.entry(tx.sender).and_modify(|e| {
+                    *e -= tx.amount;
+                });
+            }
+        }
+    });
+
+    let _ = insert_block(block.clone());
+    let _ = insert_stats(stats.clone());
+
+    for transaction in block.transactions {
+        if transaction.recipient == ic_cdk::id() {
+            let transfer = TransferArg {
+                to: Account {
+                    owner: transaction.sender,
+                    subaccount: None,
+                },
+                fee: None,
+                from_subaccount: None,
+                memo: None,
+                created_at_time: None,
+                amount: transaction.amount.into(),
+            };
+            ic_cdk::spawn(async move {
+                match icrc1_transfer(transfer, read_state(|s| s.config.bil_ledger_id)).await {
+                    Ok(_) => {
+                        ic_cdk::println!("BIL minted successfully");
+                        sub_balance(transaction.sender, transaction.amount);
+                        mutate_state(|s| {
+                            s.total_withdrawn =
+                                s.total_withdrawn.saturating_add(transaction.amount);
+                        });
+                    }
+                    Err(e) => {
+                        ic_cdk::println!("Error minting BIL: {:?}", e);
+                    }
+                }
+            });
+        } else {
+            add_balance(transaction.recipient, transaction.amount);
+            sub_balance(transaction.sender, transaction.amount);
+        }
+    }
+
+    let miner_owner = read_state(|s|
+        s.reward_account(&ic_cdk::caller()).unwrap_or(Principal::anonymous())
+    );
+    // The winning hash is a share too.
+    insert_share(
+        Share {
+            miner: ic_cdk::caller(),
+            block_height: block.header.height,
+            difficulty: block.header.difficulty,
+            timestamp: ic_cdk::api::time(),
+        },
+        read_state(|s| s.config.pplns_window)
+    );
+
+    let reward = read_state(|s| reward_at(&s.config, block.header.height));
+    if read_state(|s| s.config.pool_enabled) {
+        pay_pool_rewards(miner_owner, reward);
+    } else {
+        add_balance(miner_owner, reward);
+    }
+    mutate_state(|s| s.block_shares.clear());
+
+    let config = read_state(|s| s.config.clone());
+    if config.block_time > stats.solve_time {
+        let sec = (config.block_time - stats.solve_time) / SEC_NANOS;
+        if sec > 60 && read_state(|s| s.current_difficulty) < config.max_difficulty {
+            mutate_state(|s| {
+                s.current_difficulty = s.current_difficulty + 1;
+            });
+        }
+    } else {
+        let sec = (stats.solve_time - config.block_time) / SEC_NANOS;
+        if sec > 60 && read_state(|s| s.current_difficulty) > config.min_difficulty {
+            mutate_state(|s| {
+                s.current_difficulty = s.current_difficulty - 1;
+            });
+        }
+    }
+
+    ic_cdk::println!("Solution from miner {} accepted", ic_cdk::caller().to_text());
+
     start_next_block(1);
 
     Ok(true)
 }
 
-fn validate_solution(block: &Block) -> Result<(), String> {
+fn validate_solution(block: &Block, template_id: Option<u64>) -> Result<(), String> {
     if ic_cdk::caller() == Principal::anonymous() {
         return Err("caller is anonymous".to_string());
     }
@@ -447,6 +1049,10 @@ fn validate_solution(block: &Block) -> Result<(), String> {
         return Err("Unregistered miner".to_string());
     }
 
+    if read_state(|s| s.banned_miners.contains(&ic_cdk::caller())) {
+        return Err("Banned miner".to_string());
+    }
+
     if let Some(latest_block) = latest_block() {
         if block.header.prev_hash != latest_block.hash {
             return Err("Block references outdated chain state".to_string());
@@ -457,56 +1063,938 @@ fn validate_solution(block: &Block) -> Result<(), String> {
         }
     }
 
-    if block.header.height != block_count() {
-        return Err("Block height mismatch".to_string());
+    if block.header.height != block_count() {
+        return Err("Block height mismatch".to_string());
+    }
+
+    let issued = read_state(|s| match template_id {
+        Some(id) => s.templates.get(&id).is_some_and(|t| t.same_template(block)),
+        // Miners predating template ids don't send one.
+        None => s.templates.values().any(|t| t.same_template(block)),
+    });
+    if !issued {
+        return Err("Block does not match an issued template".to_string());
+    }
+
+    // Templates issued before withdrawals were paused may still carry some.
+    let has_withdrawals = block.transactions.iter().any(|tx| tx.recipient == ic_cdk::id());
+    if has_withdrawals && read_state(|s| s.paused.withdrawals) {
+        return Err("withdrawals are paused".to_string());
+    }
+
+    let hash_value = Block::pow_hash(&block.header, block.nonce);
+    if hash_value.leading_zeros() < block.header.difficulty {
+        return Err("Invalid solution".to_string());
+    }
+
+    Ok(())
+}
+
+/// Splits `reward` over the owners of the shares in the PPLNS window.
+fn pay_pool_rewards(finder: Principal, reward: u64) {
+    let shares: Vec<(Principal, u128)> = read_state(|s|
+        window_shares()
+            .into_iter()
+            .filter(|share| !s.banned_miners.contains(&share.miner))
+            .filter_map(|share|
+                s.reward_account(&share.miner).map(|owner| (owner, share.work()))
+            )
+            .collect()
+    );
+
+    for (owner, amount) in split_reward(reward, finder, &shares) {
+        add_balance(owner, amount);
+    }
+}
+
+/// The calling miner, if registered. The call counts as a heartbeat.
+fn registered_miner() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if !read_state(|s| s.miner_to_owner.contains_key(&caller)) {
+        return Err("Unregistered miner".to_string());
+    }
+    mutate_state(|s| s.heartbeat(caller, ic_cdk::api::time()));
+    Ok(caller)
+}
+
+/// Called by a miner when its owner pauses or resumes it.
+#[update(hidden = true)]
+fn notify_miner_paused(paused: bool) -> Result<(), String> {
+    let miner = registered_miner()?;
+    mutate_state(|s| {
+        if paused {
+            s.paused_miners.insert(miner);
+        } else {
+            s.paused_miners.remove(&miner);
+        }
+    });
+    Ok(())
+}
+
+/// Called by a miner when its owner redirects its rewards; `None` pays the owner again.
+#[update(hidden = true)]
+fn notify_reward_account(account: Option<Principal>) -> Result<(), String> {
+    let miner = registered_miner()?;
+    if account == Some(Principal::anonymous()) {
+        return Err("reward account is anonymous".to_string());
+    }
+    mutate_state(|s| {
+        match account {
+            Some(account) => s.miner_to_reward_account.insert(miner, account),
+            None => s.miner_to_reward_account.remove(&miner),
+        }
+    });
+    Ok(())
+}
+
+/// Called by a miner when its owner hands it over to `new_owner`.
+#[update(hidden = true)]
+fn notify_owner_change(new_owner: Principal) -> Result<(), String> {
+    let miner = registered_miner()?;
+    if new_owner == Principal::anonymous() {
+        return Err("new owner is anonymous".to_string());
+    }
+    mutate_state(|s| s.transfer_miner(miner, new_owner));
+    set_miner_owner(miner, new_owner);
+    ic_cdk::println!("Miner {} transferred to {}", miner.to_text(), new_owner.to_text());
+    Ok(())
+}
+
+/// Called by a miner when its spending policy stops or resumes mining.
+#[update(hidden = true)]
+fn notify_miner_status(status: MinerStatus, balance: u64) -> Result<(), String> {
+    let miner = registered_miner()?;
+    mutate_state(|s| {
+        s.miner_status.insert(miner, MinerStatusReport {
+            status,
+            balance,
+            updated_at: ic_cdk::api::time(),
+        });
+    });
+    ic_cdk::println!("Miner {} reported {:?}", miner.to_text(), status);
+    Ok(())
+}
+
+#[query]
+fn get_miner_status(miner: Principal) -> Option<MinerStatusReport> {
+    read_state(|s| s.miner_status.get(&miner).cloned())
+}
+
+#[query]
+fn get_reward_account(miner: Principal) -> Option<Principal> {
+    read_state(|s| s.reward_account(&miner))
+}
+
+/// Records a partial solution of the current block, for hashrate estimates and
+/// PPLNS payouts. Kept for miners that submit one share per mining step.
+#[update(hidden = true)]
+fn submit_share(block_height: u64, nonce: u128, template_id: Option<u64>) -> Result<bool, String> {
+    let difficulty = read_state(|s| s.config.share_difficulty);
+    record_shares(ic_cdk::caller(), block_height, &[nonce], difficulty, template_id).map(|_| true)
+}
+
+/// Records every share a mining step found at `difficulty`, which miners raise
+/// above the configured share difficulty to keep the count per step small.
+/// Each share is credited the work its difficulty stands for.
+#[update(hidden = true)]
+fn submit_shares(
+    block_height: u64,
+    nonces: Vec<u128>,
+    difficulty: u32,
+    template_id: Option<u64>
+) -> Result<u64, String> {
+    record_shares(ic_cdk::caller(), block_height, &nonces, difficulty, template_id)
+}
+
+/// Cheap checks run first, the hashes are only computed last. A batch is
+/// recorded whole or not at all.
+fn record_shares(
+    caller: Principal,
+    block_height: u64,
+    nonces: &[u128],
+    difficulty: u32,
+    template_id: Option<u64>
+) -> Result<u64, String> {
+    if read_state(|s| s.paused.solutions) {
+        return Err("solutions are paused".to_string());
+    }
+
+    if !read_state(|s| s.miner_to_owner.contains_key(&caller)) {
+        return Err("Unregistered miner".to_string());
+    }
+
+    if read_state(|s| s.banned_miners.contains(&caller)) {
+        return Err("Banned miner".to_string());
+    }
+
+    let config = read_state(|s| s.config.clone());
+    if nonces.is_empty() || nonces.len() > MAX_SHARES_PER_SUBMIT {
+        return Err(format!("submit between 1 and {} shares", MAX_SHARES_PER_SUBMIT));
+    }
+    if difficulty < config.share_difficulty {
+        return Err(format!("share difficulty must be at least {}", config.share_difficulty));
+    }
+
+    let now = ic_cdk::api::time();
+    mutate_state(|s| s.heartbeat(caller, now));
+    let limited = mutate_state(|s| {
+        let last = s.last_share_at.get(&caller).cloned().unwrap_or(0);
+        if now < last.saturating_add(config.min_share_interval) {
+            return true;
+        }
+        s.last_share_at.insert(caller, now);
+        false
+    });
+    if limited {
+        return Err("Share rate limit exceeded".to_string());
+    }
+
+    let template = read_state(|s| match template_id {
+        Some(id) => s.templates.get(&id).cloned(),
+        None => s.current_block.clone(),
+    });
+    let block = match template {
+        Some(block) if block.header.height == block_height && block_height == block_count() =>
+            block,
+        _ => {
+            return Err("Stale share".to_string());
+        }
+    };
+
+    let unique: std::collections::BTreeSet<u128> = nonces.iter().cloned().collect();
+    if unique.len() != nonces.len() {
+        return Err("Duplicate share".to_string());
+    }
+    if read_state(|s| nonces.iter().any(|nonce| s.block_shares.contains(&(caller, *nonce)))) {
+        return Err("Duplicate share".to_string());
+    }
+
+    let invalid = nonces
+        .iter()
+        .any(|nonce| Block::pow_hash(&block.header, *nonce).leading_zeros() < difficulty);
+    if invalid {
+        return Err("Invalid share".to_string());
+    }
+
+    mutate_state(|s| {
+        for nonce in nonces {
+            s.block_shares.insert((caller, *nonce));
+        }
+    });
+    for _ in nonces {
+        insert_share(
+            Share {
+                miner: caller,
+                block_height,
+                difficulty,
+                timestamp: now,
+            },
+            config.pplns_window
+        );
+    }
+
+    Ok(nonces.len() as u64)
+}
+
+#[update(hidden = true)]
+async fn distribute_block(block: Block, template_id: u64, start: u64) -> Result<(), String> {
+    if ic_cdk::caller() != ic_cdk::id() {
+        return Err("caller is not allowed".to_string());
+    }
+
+    let miners = read_state(|s|
+        s.miner_to_owner
+            .keys()
+            .filter(|miner| !s.banned_miners.contains(miner) && !s.paused_miners.contains(miner))
+            // Out of cycles until topped up; the miner reports when it resumes.
+            .filter(|miner| {
+                s.miner_status
+                    .get(miner)
+                    .map_or(true, |report| report.status != MinerStatus::OutOfCycles)
+            })
+            // Frozen, stopped or deleted; a passing health check lets it back in.
+            .filter(|miner| s.miner_health.get(miner).map_or(true, |h| h.is_healthy()))
+            .cloned()
+            .collect::<Vec<Principal>>()
+    );
+    let batch_start = std::cmp::min(start as usize, miners.len());
+    let batch_size = read_state(|s| s.config.block_batch_size) as usize;
+    let batch_end = std::cmp::min((batch_start as usize) + batch_size, miners.len());
+
+    ic_cdk::println!("Distributing block to miners {} to {}", batch_start, batch_end);
+    
+    for miner in &miners[batch_start..batch_end] {
+        push_block(block.clone(), template_id, *miner);
+    }
+
+    if batch_end < miners.len() {
+        ic_cdk::spawn(async move {
+            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "distribute_block", (
+                block,
+                template_id,
+                batch_end as u64,
+            )).await;
+        });
+    }
+
+    Ok(())
+}
+
+fn distribute_template(block: Block, template_id: u64) {
+    if read_state(|s| s.config.distribution_mode) == DistributionMode::Pull {
+        return;
+    }
+    ic_cdk::spawn(async move {
+        let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "distribute_block", (
+            block,
+            template_id,
+            0 as u64,
+        )).await;
+    });
+}
+
+fn push_block(block: Block, template_id: u64, miner: Principal) {
+    let share_difficulty = Some(read_state(|s| s.config.share_difficulty));
+    // The extranonce doubles as the miner id, miners derive their nonce range from it.
+    let miner_id = mutate_state(|s| s.assign_extranonce(miner));
+    ic_cdk::spawn(async move {
+        let res: Result<(), _> = ic_cdk::api::call::call(miner, "push_block", (
+            block,
+            miner_id,
+            share_difficulty,
+            Some(template_id),
+        )).await;
+        let now = ic_cdk::api::time();
+        mutate_state(|s| {
+            let health = s.miner_health.entry(miner).or_default();
+            match res {
+                Ok(()) => health.push_succeeded(now),
+                Err((code, msg)) => health.push_failed(format!("{:?} - {}", code, msg), now),
+            }
+        });
+    });
+}
+
+/// Checks the cycle balance and run status of every miner, in batches like `distribute_block`.
+#[update(hidden = true)]
+async fn check_miner_health(start: u64) -> Result<(), String> {
+    if ic_cdk::caller() != ic_cdk::id() {
+        return Err("caller is not allowed".to_string());
+    }
+
+    let miners = read_state(|s| s.miner_to_owner.keys().cloned().collect::<Vec<Principal>>());
+    let batch_size = read_state(|s| s.config.block_batch_size) as usize;
+    let batch_start = std::cmp::min(start as usize, miners.len());
+    let batch_end = std::cmp::min(batch_start + batch_size, miners.len());
+
+    for miner in &miners[batch_start..batch_end] {
+        let miner = *miner;
+        ic_cdk::spawn(async move {
+            let res = canister_status(miner).await;
+            let now = ic_cdk::api::time();
+            mutate_state(|s| {
+                let health = s.miner_health.entry(miner).or_default();
+                match res {
+                    Ok(response) => {
+                        let status = MinerCanisterStatus::from(response);
+                        health.checked(status.status, status.cycles, now);
+                    }
+                    Err(e) => health.check_failed(format!("{} - {:?}", e.method, e.reason), now),
+                }
+            });
+        });
+    }
+
+    if batch_end < miners.len() {
+        ic_cdk::spawn(async move {
+            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "check_miner_health", (
+                batch_end as u64,
+            )).await;
+        });
+    }
+
+    Ok(())
+}
+
+/// Live cycle balance, memory and module hash of `miner`, for its owner and auditors.
+#[update]
+async fn get_miner_canister_status(miner: Principal) -> Result<MinerCanisterStatus, String> {
+    let caller = ic_cdk::caller();
+    let owner = read_state(|s| s.miner_to_owner.get(&miner).cloned()).ok_or_else(||
+        "miner not found".to_string()
+    )?;
+    if caller != owner {
+        authorize(&caller, Role::Auditor)?;
+    }
+
+    let response = canister_status(miner).await.map_err(|e|
+        format!("{} - {:?}", e.method, e.reason)
+    )?;
+    let status = MinerCanisterStatus::from(response);
+
+    // Fresh enough to count as a health check, e.g. right after a top-up.
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        s.miner_health.entry(miner).or_default().checked(status.status, status.cycles, now);
+    });
+
+    Ok(status)
+}
+
+/// Retires `miner` for good: its cycles go to `cycles_to`, then the canister is
+/// stopped and deleted and the miner leaves every registry. `cycles_to` must be
+/// a canister, such as a cycles wallet or another miner, which gets them as a
+/// top-up; without it the cycles go back to the backend. Returns the cycles moved.
+#[update]
+async fn decommission_miner(miner: Principal, cycles_to: Option<Principal>) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let owner = read_state(|s| s.miner_to_owner.get(&miner).cloned()).ok_or_else(||
+        "miner not found".to_string()
+    )?;
+    if caller != owner {
+        return Err("caller is not the owner".to_string());
+    }
+    let to = cycles_to.unwrap_or_else(ic_cdk::id);
+    if to == miner {
+        return Err("cycles cannot go to the miner being decommissioned".to_string());
+    }
+    if !is_canister(&to) {
+        return Err("cycles can only go to a canister".to_string());
+    }
+    // Miners take cycles through `receive`, which raises their spending baseline.
+    let to_miner = read_state(|s| s.miner_to_owner.contains_key(&to));
+
+    let _guard = DecommissionGuard::new(miner)?;
+    // No more blocks while it winds down.
+    let was_paused = !mutate_state(|s| s.paused_miners.insert(miner));
+
+    let status = match canister_status(miner).await {
+        Ok(response) => MinerCanisterStatus::from(response).status,
+        Err(e) => {
+            return Err(unpause_after(miner, was_paused, format!("{} - {:?}", e.method, e.reason)));
+        }
+    };
+
+    // Only this flow stops miners, after their cycles are out: a stopped miner
+    // is one an earlier attempt left behind before deleting it.
+    let refunded = if status == CanisterRunStatus::Stopped {
+        0
+    } else {
+        let res: Result<(Result<u64, String>,), _> = ic_cdk::api::call::call(
+            miner,
+            "decommission",
+            (to, to_miner)
+        ).await;
+        let refunded = match res {
+            Ok((Ok(amount),)) => amount,
+            Ok((Err(e),)) => {
+                return Err(unpause_after(miner, was_paused, e));
+            }
+            Err((code, msg)) => {
+                return Err(unpause_after(miner, was_paused, format!("{:?} - {}", code, msg)));
+            }
+        };
+        stop_canister(miner).await.map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
+        refunded
+    };
+    delete_canister(miner).await.map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
+
+    let now = ic_cdk::api::time();
+    mutate_state(|s| {
+        s.retire_miner(
+            miner,
+            RetiredMiner {
+                owner,
+                cycles_refunded: refunded,
+                cycles_to: to,
+                retired_at: now,
+            },
+            now
+        );
+    });
+    remove_miner(miner);
+    ic_cdk::println!("Miner {} decommissioned, {} cycles sent to {}", miner, refunded, to);
+
+    Ok(refunded)
+}
+
+/// Opaque ids, the class the management canister assigns canisters, end in 0x01.
+fn is_canister(principal: &Principal) -> bool {
+    principal.as_slice().last() == Some(&0x01)
+}
+
+fn unpause_after(miner: Principal, was_paused: bool, error: String) -> String {
+    if !was_paused {
+        mutate_state(|s| s.paused_miners.remove(&miner));
+    }
+    error
+}
+
+#[query]
+fn get_retired_miner(miner: Principal) -> Option<RetiredMiner> {
+    read_state(|s| s.retired_miners.get(&miner).cloned())
+}
+
+#[query]
+fn get_miner_health(miner: Principal) -> Option<MinerHealthReport> {
+    read_state(|s| {
+        if !s.miner_to_owner.contains_key(&miner) {
+            return None;
+        }
+        let health = s.miner_health.get(&miner).cloned().unwrap_or_default();
+        Some(MinerHealthReport::new(miner, health))
+    })
+}
+
+/// Health of every miner `user` owns.
+#[query]
+fn get_miners_health(user: Principal) -> Vec<MinerHealthReport> {
+    read_state(|s| {
+        s.principal_to_miner
+            .get(&user)
+            .map(|miners| {
+                miners
+                    .iter()
+                    .map(|miner| {
+                        let health = s.miner_health.get(miner).cloned().unwrap_or_default();
+                        MinerHealthReport::new(*miner, health)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+fn start_next_block(sec: u64) {
+    ic_cdk::println!("Starting next block in {} seconds", sec);
+    ic_cdk_timers::set_timer(std::time::Duration::from_secs(sec), || {
+        create_block();
+    });
+}
+
+fn create_block() {
+    if read_state(|s| s.paused.block_production) {
+        ic_cdk::println!("Block production is paused");
+        start_next_block(20);
+        return;
+    }
+
+    let transactions = read_state(|s| s.block_transactions(ic_cdk::id()));
+    let prev_block = latest_block().unwrap();
+    if transactions.is_empty() {
+        let mine_empty = match read_state(|s| s.config.empty_block_policy) {
+            EmptyBlockPolicy::Skip => false,
+            EmptyBlockPolicy::Always => true,
+            EmptyBlockPolicy::AfterIdle(idle) =>
+                ic_cdk::api::time().saturating_sub(prev_block.header.timestamp) >= idle,
+        };
+        if !mine_empty {
+            ic_cdk::println!("No transactions to include in block");
+            start_next_block(20);
+            return;
+        }
+    }
+
+    ic_cdk::println!("Creating block with {} transactions", transactions.len());
+
+    let difficulty = read_state(|s| s.current_difficulty);
+    match Block::new(&prev_block, transactions, difficulty) {
+        Ok(block) => {
+            ic_cdk::println!("Block created successfully!");
+            let template_id = mutate_state(|s| s.issue_template(block.clone()));
+            distribute_template(block, template_id);
+        }
+        Err(e) => {
+            ic_cdk::println!("Error creating block: {:?}", e);
+        }
+    }
+}
+
+fn windoge_ledger() -> IcLedger {
+    IcLedger::new(read_state(|s| s.config.windoge_ledger_id), LedgerApi::GetTransaction)
+}
+
+#[derive(CandidType, candid::Deserialize)]
+struct TransferArg {
+    pub to: Account,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<serde_bytes::ByteBuf>,
+    pub from_subaccount: Option<serde_bytes::ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: candid::Nat,
+}
+
+#[derive(CandidType, candid::Deserialize, Debug)]
+enum TransferError {
+    GenericError {
+        message: String,
+        error_code: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TooOld,
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+}
+
+#[derive(CandidType, candid::Deserialize)]
+enum Result_ {
+    Ok(candid::Nat),
+    Err(TransferError),
+}
+
+async fn icrc1_transfer(transfer: TransferArg, token: Principal) -> Result<candid::Nat, String> {
+    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
+        ::call_raw(token, "icrc1_transfer", candid::encode_args((transfer,)).unwrap(), 0).await
+        .map_err(|(code, msg)| (code as i32, msg));
+    match result {
+        Ok(res) => {
+            let response = Decode!(&res, Result_).map_err(|e| format!("{:?}", e))?;
+            match response {
+                Result_::Ok(index) => Ok(index),
+                Result_::Err(e) => Err(format!("{:?}", e)),
+            }
+        }
+        Err((code, msg)) => Err(format!("Error icrc1_transfer ({}): {:?}", code, msg)),
+    }
+}
+
+async fn icrc1_fee(token: Principal) -> Result<u64, String> {
+    let (fee,): (candid::Nat,) = ic_cdk::api::call
+        ::call(token, "icrc1_fee", ()).await
+        .map_err(|(code, msg)| format!("Error icrc1_fee ({:?}): {:?}", code, msg))?;
+    nat_to_u64(fee)
+}
+
+#[derive(CandidType, candid::Deserialize)]
+struct TransferFromArgs {
+    pub spender_subaccount: Option<serde_bytes::ByteBuf>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<serde_bytes::ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, candid::Deserialize, Debug)]
+enum TransferFromError {
+    GenericError {
+        message: String,
+        error_code: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    InsufficientAllowance {
+        allowance: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TooOld,
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+}
+
+#[derive(CandidType, candid::Deserialize)]
+enum TransferFromResult {
+    Ok(candid::Nat),
+    Err(TransferFromError),
+}
+
+async fn icrc2_transfer_from(args: TransferFromArgs, token: Principal) -> Result<candid::Nat, String> {
+    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
+        ::call_raw(token, "icrc2_transfer_from", candid::encode_args((args,)).unwrap(), 0).await
+        .map_err(|(code, msg)| (code as i32, msg));
+    match result {
+        Ok(res) => {
+            let response = Decode!(&res, TransferFromResult).map_err(|e| format!("{:?}", e))?;
+            match response {
+                TransferFromResult::Ok(index) => Ok(index),
+                TransferFromResult::Err(e) => Err(format!("{:?}", e)),
+            }
+        }
+        Err((code, msg)) => Err(format!("Error icrc2_transfer_from ({}): {:?}", code, msg)),
+    }
+}
+
+#[update(hidden = true)]
+async fn transfer_exe(amount: u64) -> Result<candid::Nat, String> {
+    if ic_cdk::caller() != Principal::from_text(WINDOGE_RECEIVER).unwrap() {
+        return Err("caller is not allowed".to_string());
+    }
+
+    let transfer = TransferArg {
+        to: Account {
+            owner: Principal::from_text(WINDOGE_RECEIVER).unwrap(),
+            subaccount: None,
+        },
+        fee: None,
+        memo: None,
+        from_subaccount: None,
+        created_at_time: None,
+        amount: candid::Nat::from(amount),
+    };
+
+    audit(ic_cdk::caller(), "transfer_exe", format!("amount: {}", amount));
+
+    match icrc1_transfer(transfer, windoge_ledger().canister_id).await {
+        Ok(index) => Ok(index),
+        Err(e) => Err(e),
+    }
+}
+
+#[update]
+fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    // Admins manage operators and auditors; only controllers appoint or replace admins.
+    if role == Role::Admin || get_role(principal) == Some(Role::Admin) {
+        if !is_controller(&caller) {
+            return Err("caller is not allowed".to_string());
+        }
+    } else {
+        authorize(&caller, Role::Admin)?;
+    }
+
+    set_role(principal, role);
+    audit(caller, "grant_role", format!("{}: {:?}", principal.to_text(), role));
+
+    Ok(())
+}
+
+#[update]
+fn revoke_role(principal: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    match get_role(principal) {
+        Some(Role::Admin) if !is_controller(&caller) => {
+            return Err("caller is not allowed".to_string());
+        }
+        Some(role) => {
+            remove_role(principal);
+            audit(caller, "revoke_role", format!("{}: {:?}", principal.to_text(), role));
+        }
+        None => {
+            return Err("principal has no role".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+#[update]
+fn set_governance_principal(principal: Option<Principal>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(&caller) {
+        return Err("caller is not allowed".to_string());
+    }
+
+    mutate_state(|s| {
+        s.config.governance_principal = principal;
+    });
+    audit(caller, "set_governance_principal", format!("{:?}", principal.map(|p| p.to_text())));
+
+    Ok(())
+}
+
+#[update]
+fn update_config(args: UpgradeArgs) -> Result<Config, String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    if args.governance_principal.is_some() {
+        return Err("use set_governance_principal to change the governance principal".to_string());
+    }
+    // Payments are verified against these ledgers, only controllers may move them.
+    if args.windoge_ledger_id.is_some() || args.bil_ledger_id.is_some() {
+        return Err("ledger ids can only be changed through upgrade args".to_string());
+    }
+
+    let mut config = read_state(|s| s.config.clone());
+    config.apply(args.clone());
+    config.validate()?;
+
+    mutate_state(|s| {
+        s.config = config.clone();
+    });
+    audit(caller, "update_config", format!("{:?}", args));
+
+    Ok(config)
+}
+
+#[update]
+fn set_difficulty(difficulty: u32) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    let (min, max) = read_state(|s| (s.config.min_difficulty, s.config.max_difficulty));
+    if difficulty < min || difficulty > max {
+        return Err(format!("difficulty must be between {} and {}", min, max));
+    }
+
+    let previous = mutate_state(|s| std::mem::replace(&mut s.current_difficulty, difficulty));
+    audit(caller, "set_difficulty", format!("{} -> {}", previous, difficulty));
+
+    Ok(())
+}
+
+#[update]
+fn ban_miner(miner: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Operator)?;
+
+    if !read_state(|s| s.miner_to_owner.contains_key(&miner)) {
+        return Err("miner not found".to_string());
+    }
+
+    mutate_state(|s| {
+        s.banned_miners.insert(miner);
+    });
+    audit(caller, "ban_miner", miner.to_text());
+
+    Ok(())
+}
+
+#[update]
+fn unban_miner(miner: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Operator)?;
+
+    if !mutate_state(|s| s.banned_miners.remove(&miner)) {
+        return Err("miner is not banned".to_string());
+    }
+    audit(caller, "unban_miner", miner.to_text());
+
+    Ok(())
+}
+
+#[update]
+fn set_pause(flags: PauseFlags) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Operator)?;
+
+    let previous = mutate_state(|s| std::mem::replace(&mut s.paused, flags.clone()));
+    audit(caller, "set_pause", format!("{:?}", flags));
+
+    if flags.mining_halted() && !previous.mining_halted() {
+        ic_cdk::spawn(async {
+            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "notify_pause", (
+                0 as u64,
+            )).await;
+        });
+    } else if !flags.mining_halted() && previous.mining_halted() {
+        // Miners dropped their block when paused, hand it back out.
+        if let Some((block, template_id)) = read_state(|s| s.current_template()) {
+            distribute_template(block, template_id);
+        }
+    }
+
+    // Drops withdrawals from the candidate, or adds them back.
+    if flags.withdrawals != previous.withdrawals {
+        refresh_template();
     }
 
-    let mut hasher = RapidHasher::new(0);
-    let mut data = Vec::new();
-    data.extend_from_slice(&block.header.version.to_le_bytes());
-    data.extend_from_slice(&block.header.prev_hash.to_le_bytes());
-    data.extend_from_slice(&block.header.merkle_root.to_le_bytes());
-    data.extend_from_slice(&block.header.timestamp.to_le_bytes());
-    data.extend_from_slice(&block.nonce.to_le_bytes());
-
-    hasher.write(&data);
-    let hash64 = hasher.finish();
+    Ok(())
+}
 
-    let hash128_high = {
-        let mut hasher = RapidHasher::new(hash64);
-        hasher.write(&hash64.to_le_bytes());
-        hasher.finish()
-    };
+/// Current candidate block for `miner_id`, for miners running in pull mode.
+/// In push mode blocks only arrive through `push_block`.
+#[query]
+fn get_block_template(miner_id: Principal) -> Result<BlockTemplate, String> {
+    if read_state(|s| s.paused.mining_halted()) {
+        return Err("mining is paused".to_string());
+    }
 
-    let hash_value = ((hash128_high as u128) << 64) | (hash64 as u128);
-    if hash_value.leading_zeros() < block.header.difficulty {
-        return Err("Invalid solution".to_string());
+    // Miners match this message to stop polling.
+    if read_state(|s| s.config.distribution_mode) == DistributionMode::Push {
+        return Err("blocks are pushed to miners, templates are not served".to_string());
     }
 
-    Ok(())
+    read_state(|s| {
+        if s.banned_miners.contains(&miner_id) {
+            return Err("Banned miner".to_string());
+        }
+        if !s.miner_to_owner.contains_key(&miner_id) {
+            return Err("Unregistered miner".to_string());
+        }
+        // Assigned at registration, or for older miners at upgrade.
+        let extranonce = match s.miner_to_extranonce.get(&miner_id) {
+            Some(extranonce) => *extranonce,
+            None => {
+                return Err("no extranonce assigned".to_string());
+            }
+        };
+
+        let (block, template_id) = match s.current_template() {
+            // A solved block stays current until `create_block` issues the next one.
+            Some((block, template_id)) if block.header.height == block_count() => (block, template_id),
+            _ => {
+                return Err("no block template available".to_string());
+            }
+        };
+
+        let (extranonce_start, extranonce_end) = nonce_range(extranonce);
+        Ok(BlockTemplate {
+            template_id,
+            difficulty: block.header.difficulty,
+            block,
+            miner_id: extranonce,
+            share_difficulty: s.config.share_difficulty,
+            extranonce_start,
+            extranonce_end,
+        })
+    })
+}
+
+#[query]
+fn get_pause_status() -> PauseFlags {
+    read_state(|s| s.paused.clone())
 }
 
+/// Tells miners to stop hashing, in batches like `distribute_block`.
 #[update(hidden = true)]
-async fn distribute_block(block: Block, start: u64) -> Result<(), String> {
+async fn notify_pause(start: u64) -> Result<(), String> {
     if ic_cdk::caller() != ic_cdk::id() {
         return Err("caller is not allowed".to_string());
     }
 
     let miners = read_state(|s| s.miner_to_owner.keys().cloned().collect::<Vec<Principal>>());
+    let batch_size = read_state(|s| s.config.block_batch_size) as usize;
     let batch_start = std::cmp::min(start as usize, miners.len());
-    let batch_end = std::cmp::min((batch_start as usize) + BLOCK_BATCH_SIZE, miners.len());
+    let batch_end = std::cmp::min(batch_start + batch_size, miners.len());
 
-    ic_cdk::println!("Distributing block to miners {} to {}", batch_start, batch_end);
-    
-    for i in batch_start..batch_end {
-        let miner = &miners[i];
-        push_block(block.clone(), miner.clone(), (i + 1) as u32);
+    for miner in &miners[batch_start..batch_end] {
+        let miner = *miner;
+        ic_cdk::spawn(async move {
+            let _: Result<(), _> = ic_cdk::api::call::call(miner, "pause_mining", ()).await;
+        });
     }
 
     if batch_end < miners.len() {
         ic_cdk::spawn(async move {
-            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "distribute_block", (
-                block,
+            let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "notify_pause", (
                 batch_end as u64,
             )).await;
         });
@@ -515,198 +2003,340 @@ async fn distribute_block(block: Block, start: u64) -> Result<(), String> {
     Ok(())
 }
 
-fn push_block(block: Block, miner: Principal, miner_id: u32) {
-    ic_cdk::spawn(async move {
-        let _: Result<(), _> = ic_cdk::api::call::call(miner, "push_block", (
-            block,
-            miner_id,
-        )).await;
-    });
-}
+/// Upgrades every registered miner not yet running `wasm_hash`, the embedded miner
+/// wasm by default, `batch_size` miners at a time.
+#[update]
+fn start_fleet_upgrade(
+    wasm_hash: Option<Vec<u8>>,
+    batch_size: Option<u64>
+) -> Result<FleetUpgrade, String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    if read_state(|s| s.fleet_upgrade.as_ref().is_some_and(|u| u.is_running())) {
+        return Err("a fleet upgrade is already running".to_string());
+    }
+    let target = wasm_hash.unwrap_or_else(|| read_state(|s| s.miner_wasm_hash.clone()));
+    if !has_wasm(&target) {
+        return Err("wasm not found".to_string());
+    }
+    let batch_size = batch_size.unwrap_or(UPGRADE_BATCH_SIZE);
+    if batch_size == 0 {
+        return Err("batch size must be greater than 0".to_string());
+    }
 
-fn start_next_block(sec: u64) {
-    ic_cdk::println!("Starting next block in {} seconds", sec);
-    ic_cdk_timers::set_timer(std::time::Duration::from_secs(sec), || {
-        create_block();
-    });
+    let upgrade = mutate_state(|s| {
+        let queue = upgrade_queue(s, &target);
+        if queue.is_empty() {
+            return Err("every miner already runs this wasm".to_string());
+        }
+        queue_miners(s, &queue);
+        let upgrade = FleetUpgrade::new(
+            FleetUpgradeKind::Upgrade,
+            target.clone(),
+            batch_size,
+            queue,
+            ic_cdk::api::time()
+        );
+        s.fleet_upgrade = Some(upgrade.clone());
+        Ok(upgrade)
+    })?;
+    audit(
+        caller,
+        "start_fleet_upgrade",
+        format!("{}: {} miners, batches of {}", hash_to_hex(&target), upgrade.total, batch_size)
+    );
+
+    schedule_fleet_upgrade();
+    Ok(upgrade)
 }
 
-fn create_block() {
-    let transactions = read_state(|s| s.mempool.clone());
-    if transactions.is_empty() {
-        ic_cdk::println!("No transactions to include in block");
-        start_next_block(20);
-        return;
-    }
+/// Moves the miners the last fleet upgrade reached back to the wasm they ran before it.
+#[update]
+fn rollback_fleet_upgrade(batch_size: Option<u64>) -> Result<FleetUpgrade, String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
 
-    ic_cdk::println!("Creating block with {} transactions", transactions.len());
+    let last = read_state(|s| s.fleet_upgrade.clone()).ok_or_else(||
+        "no fleet upgrade to roll back".to_string()
+    )?;
+    if last.is_running() {
+        return Err("stop the running fleet upgrade first".to_string());
+    }
+    if last.kind != FleetUpgradeKind::Upgrade {
+        return Err("the last fleet upgrade is already a rollback".to_string());
+    }
+    let batch_size = batch_size.unwrap_or(last.batch_size);
+    if batch_size == 0 {
+        return Err("batch size must be greater than 0".to_string());
+    }
 
-    let prev_block = latest_block().unwrap();
-    let difficulty = read_state(|s| s.current_difficulty);
-    match Block::new(&prev_block, transactions, difficulty) {
-        Ok(block) => {
-            ic_cdk::println!("Block created successfully!");
-            mutate_state(|s| {
-                s.current_block = Some(block.clone());
-            });
-            ic_cdk::spawn(async move {
-                let _: Result<(), _> = ic_cdk::api::call::call(ic_cdk::id(), "distribute_block", (
-                    block,
-                    0 as u64
-                )).await;
-            });
-        }
-        Err(e) => {
-            ic_cdk::println!("Error creating block: {:?}", e);
+    let upgrade = mutate_state(|s| {
+        let queue = rollback_queue(s, &last.target);
+        if queue.is_empty() {
+            return Err("no miner can be rolled back".to_string());
         }
-    }
-}
+        queue_miners(s, &queue);
+        let upgrade = FleetUpgrade::new(
+            FleetUpgradeKind::Rollback,
+            last.target.clone(),
+            batch_size,
+            queue,
+            ic_cdk::api::time()
+        );
+        s.fleet_upgrade = Some(upgrade.clone());
+        Ok(upgrade)
+    })?;
+    audit(
+        caller,
+        "rollback_fleet_upgrade",
+        format!("{}: {} miners", hash_to_hex(&last.target), upgrade.total)
+    );
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Account {
-    pub owner: Principal,
-    pub subaccount: Option<serde_bytes::ByteBuf>,
+    schedule_fleet_upgrade();
+    Ok(upgrade)
 }
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Burn {
-    pub from: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
+/// Stops after the miner being upgraded; `resume_fleet_upgrade` continues with the rest.
+#[update]
+fn stop_fleet_upgrade() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    mutate_state(|s| {
+        match s.fleet_upgrade.as_mut() {
+            Some(upgrade) if upgrade.is_running() => {
+                upgrade.state = FleetUpgradeState::Stopped;
+                Ok(())
+            }
+            _ => Err("no fleet upgrade is running".to_string()),
+        }
+    })?;
+    audit(caller, "stop_fleet_upgrade", String::new());
+
+    Ok(())
 }
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Mint1 {
-    pub to: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
+#[update]
+fn resume_fleet_upgrade() -> Result<FleetUpgrade, String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    let upgrade = mutate_state(|s| {
+        match s.fleet_upgrade.as_mut() {
+            Some(upgrade) if upgrade.state == FleetUpgradeState::Stopped => {
+                upgrade.state = FleetUpgradeState::Running;
+                Ok(upgrade.clone())
+            }
+            _ => Err("no fleet upgrade is stopped".to_string()),
+        }
+    })?;
+    audit(caller, "resume_fleet_upgrade", format!("{} miners left", upgrade.queue.len()));
+
+    schedule_fleet_upgrade();
+    Ok(upgrade)
 }
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Transfer {
-    pub to: Account,
-    pub fee: Option<candid::Nat>,
-    pub from: Account,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
+#[query]
+fn get_fleet_upgrade() -> Option<FleetUpgrade> {
+    read_state(|s| s.fleet_upgrade.clone())
 }
 
-#[derive(CandidType, candid::Deserialize)]
-pub struct Transaction1 {
-    pub burn: Option<Burn>,
-    pub kind: String,
-    pub mint: Option<Mint1>,
-    pub timestamp: u64,
-    pub index: candid::Nat,
-    pub transfer: Option<Transfer>,
+#[query]
+fn get_miner_version(miner: Principal) -> Option<MinerVersion> {
+    read_state(|s| s.miner_versions.get(&miner).cloned())
 }
 
-async fn fetch_block(block_height: candid::Nat) -> Result<Transaction1, String> {
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
-        ::call_raw(
-            Principal::from_text(WINDOGE_LEDGER_ID).unwrap(),
-            "get_transaction",
-            candid::encode_args((block_height,)).unwrap(),
-            0
-        ).await
-        .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let block = Decode!(&res, Option<Transaction1>).map_err(|e| format!("{:?}", e))?;
-            if let Some(block) = block {
-                ic_cdk::println!("transaction: {:?}", block.index);
-                Ok(block)
-            } else {
-                Err("Block not found".to_string())
-            }
-        }
-        Err((code, msg)) =>
-            Err(format!("Error while calling minter canister ({}): {:?}", code, msg)),
+/// Stores a miner wasm the backend never embedded, such as the one running on
+/// miners spawned before versions were tracked, so upgrades off it can roll back.
+#[update]
+fn upload_miner_wasm(module: Vec<u8>) -> Result<Vec<u8>, String> {
+    let caller = ic_cdk::caller();
+    authorize(&caller, Role::Admin)?;
+
+    if module.is_empty() {
+        return Err("empty wasm".to_string());
     }
+    let hash = wasm_hash(&module);
+    insert_wasm(hash.clone(), module, ic_cdk::api::time());
+    audit(caller, "upload_miner_wasm", hash_to_hex(&hash));
+
+    Ok(hash)
 }
 
-#[derive(CandidType, candid::Deserialize)]
-struct TransferArg {
-    pub to: Account,
-    pub fee: Option<candid::Nat>,
-    pub memo: Option<serde_bytes::ByteBuf>,
-    pub from_subaccount: Option<serde_bytes::ByteBuf>,
-    pub created_at_time: Option<u64>,
-    pub amount: candid::Nat,
+#[query]
+fn get_wasm_versions() -> Vec<WasmVersion> {
+    wasm_versions()
 }
 
-#[derive(CandidType, candid::Deserialize, Debug)]
-enum TransferError {
-    GenericError {
-        message: String,
-        error_code: candid::Nat,
-    },
-    TemporarilyUnavailable,
-    BadBurn {
-        min_burn_amount: candid::Nat,
-    },
-    Duplicate {
-        duplicate_of: candid::Nat,
-    },
-    BadFee {
-        expected_fee: candid::Nat,
-    },
-    CreatedInFuture {
-        ledger_time: u64,
-    },
-    TooOld,
-    InsufficientFunds {
-        balance: candid::Nat,
-    },
+fn queue_miners(state: &mut State, miners: &[Principal]) {
+    let now = ic_cdk::api::time();
+    for miner in miners {
+        let version = state.miner_versions
+            .entry(*miner)
+            .or_insert_with(|| MinerVersion::unknown(now));
+        version.status = UpgradeStatus::Pending;
+        version.updated_at = now;
+    }
 }
 
-#[derive(CandidType, candid::Deserialize)]
-enum Result_ {
-    Ok(candid::Nat),
-    Err(TransferError),
+fn schedule_fleet_upgrade() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(upgrade_next_batch());
+    });
 }
 
-async fn icrc1_transfer(transfer: TransferArg, token: Principal) -> Result<candid::Nat, String> {
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
-        ::call_raw(token, "icrc1_transfer", candid::encode_args((transfer,)).unwrap(), 0).await
-        .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let response = Decode!(&res, Result_).map_err(|e| format!("{:?}", e))?;
-            match response {
-                Result_::Ok(index) => Ok(index),
-                Result_::Err(e) => Err(format!("{:?}", e)),
+/// Upgrades the next batch of the running fleet upgrade and schedules the one after.
+/// Every outcome is saved as soon as it is known, so stopping or upgrading the
+/// backend never loses progress.
+async fn upgrade_next_batch() {
+    let _guard = match FleetUpgradeGuard::new() {
+        Ok(guard) => guard,
+        Err(_) => {
+            return;
+        }
+    };
+
+    let upgrade = match read_state(|s| s.fleet_upgrade.clone()) {
+        Some(upgrade) if upgrade.is_running() => upgrade,
+        _ => {
+            return;
+        }
+    };
+
+    let batch = upgrade.next_batch();
+    if batch.is_empty() {
+        // Nothing left, e.g. a record saved running with an empty queue.
+        mutate_state(|s| {
+            if let Some(fleet) = s.fleet_upgrade.as_mut() {
+                fleet.complete_if_empty();
             }
+        });
+    }
+
+    for miner in batch {
+        if !read_state(|s| s.fleet_upgrade.as_ref().is_some_and(|u| u.is_running())) {
+            return;
+        }
+
+        let result = upgrade_miner(miner, upgrade.kind, &upgrade.target).await;
+        let now = ic_cdk::api::time();
+        mutate_state(|s| {
+            let version = s.miner_versions
+                .entry(miner)
+                .or_insert_with(|| MinerVersion::unknown(now));
+            match &result {
+                Ok(hash) if upgrade.kind == FleetUpgradeKind::Upgrade => {
+                    version.upgraded(hash.clone(), now);
+                }
+                Ok(_) => version.rolled_back(now),
+                Err(e) => version.fail(e.clone(), now),
+            }
+            if let Some(fleet) = s.fleet_upgrade.as_mut() {
+                fleet.record(miner, result.is_ok(), now);
+            }
+        });
+        if let Err(e) = result {
+            ic_cdk::println!("Upgrade of miner {} failed: {}", miner.to_text(), e);
         }
-        Err((code, msg)) => Err(format!("Error icrc1_transfer ({}): {:?}", code, msg)),
     }
-}
 
-#[update(hidden = true)]
-async fn transfer_exe(amount: u64) -> Result<candid::Nat, String> {
-    if ic_cdk::caller() != Principal::from_text(WINDOGE_RECEIVER).unwrap() {
-        return Err("caller is not allowed".to_string());
+    match read_state(|s| s.fleet_upgrade.clone()) {
+        Some(upgrade) if upgrade.is_running() => schedule_fleet_upgrade(),
+        Some(upgrade) if upgrade.state == FleetUpgradeState::Completed =>
+            audit(
+                ic_cdk::id(),
+                "fleet_upgrade_completed",
+                format!(
+                    "{:?} {}: {} upgraded, {} failed",
+                    upgrade.kind,
+                    hash_to_hex(&upgrade.target),
+                    upgrade.upgraded,
+                    upgrade.failed
+                )
+            ),
+        _ => {}
     }
+}
 
-    let transfer = TransferArg {
-        to: Account {
-            owner: Principal::from_text(WINDOGE_RECEIVER).unwrap(),
-            subaccount: None,
-        },
-        fee: None,
-        memo: None,
-        from_subaccount: None,
-        created_at_time: None,
-        amount: candid::Nat::from(amount),
+/// Installs the wasm `kind` calls for on `miner` and returns its hash.
+async fn upgrade_miner(
+    miner: Principal,
+    kind: FleetUpgradeKind,
+    target: &[u8]
+) -> Result<Vec<u8>, String> {
+    let (owner, previous) = read_state(|s| {
+        (
+            s.miner_to_owner.get(&miner).cloned(),
+            s.miner_versions.get(&miner).and_then(|v| v.previous_hash.clone()),
+        )
+    });
+    let owner = owner.ok_or_else(|| "miner not found".to_string())?;
+    let hash = match kind {
+        FleetUpgradeKind::Upgrade => {
+            let current = installed_hash(miner).await?;
+            // A rollback reinstalls what runs now, so it has to be restorable first.
+            if current != target && !has_wasm(&current) {
+                return Err(
+                    format!(
+                        "installed wasm {} is not registered, upload it before upgrading",
+                        hash_to_hex(&current)
+                    )
+                );
+            }
+            target.to_vec()
+        }
+        FleetUpgradeKind::Rollback => previous.ok_or_else(|| "no previous version".to_string())?,
     };
+    let wasm = get_wasm(&hash).ok_or_else(|| "wasm not found".to_string())?;
 
-    match icrc1_transfer(transfer, Principal::from_text(WINDOGE_LEDGER_ID).unwrap()).await {
-        Ok(index) => Ok(index),
-        Err(e) => Err(e),
+    // Miners prefer their persisted owner; the argument covers miners that predate it.
+    let arg = Encode!(&owner).unwrap();
+    upgrade_code(miner, wasm, arg).await.map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
+
+    Ok(hash)
+}
+
+/// The wasm hash the miner runs. Miners spawned before versions were tracked
+/// are asked the management canister once and remembered.
+async fn installed_hash(miner: Principal) -> Result<Vec<u8>, String> {
+    let known = read_state(|s| s.miner_versions.get(&miner).and_then(|v| v.wasm_hash.clone()));
+    if let Some(hash) = known {
+        return Ok(hash);
     }
+    let status = canister_status(miner).await.map_err(|e|
+        format!("{} - {:?}", e.method, e.reason)
+    )?;
+    let hash = status.module_hash.ok_or_else(|| "miner has no module installed".to_string())?;
+    mutate_state(|s| {
+        let version = s.miner_versions
+            .entry(miner)
+            .or_insert_with(|| MinerVersion::unknown(ic_cdk::api::time()));
+        version.wasm_hash = Some(hash.clone());
+    });
+    Ok(hash)
+}
+
+#[query]
+fn get_role_of(principal: Principal) -> Option<Role> {
+    role_of(&principal)
+}
+
+#[query]
+fn get_roles() -> Result<Vec<(Principal, Role)>, String> {
+    authorize(&ic_cdk::caller(), Role::Auditor)?;
+    Ok(all_roles())
+}
+
+#[query]
+fn get_audit_log(start: u64, length: u64) -> Result<Vec<AuditEntry>, String> {
+    authorize(&ic_cdk::caller(), Role::Auditor)?;
+    Ok(audit_entries(start, std::cmp::min(length, MAX_PAGE_SIZE)))
+}
+
+#[query]
+fn get_audit_log_length() -> u64 {
+    audit_entry_count()
 }
 
 type Balance = candid::Nat;
@@ -755,7 +2385,7 @@ pub enum TransferResult {
 async fn burn_exe(args: BurnArgs) -> Result<candid::Nat, String> {
     let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call
         ::call_raw(
-            Principal::from_text(WINDOGE_LEDGER_ID).unwrap(),
+            windoge_ledger().canister_id,
             "burn",
             candid::encode_args((args,)).unwrap(),
             0
@@ -800,6 +2430,65 @@ struct LeaderBoardEntry {
     block_count: u64,
 }
 
+/// Per-block time, difficulty and cycles burned at heights `start..start + length`.
+#[query]
+fn get_block_points(start: u64, length: u64) -> Vec<BlockPoint> {
+    block_points(start, std::cmp::min(length, MAX_PAGE_SIZE))
+}
+
+#[query]
+fn get_hashrate_history(start: u64, length: u64, window: u64) -> Vec<HashratePoint> {
+    hashrate_history(
+        start,
+        std::cmp::min(length, MAX_PAGE_SIZE),
+        std::cmp::min(window, MAX_PAGE_SIZE)
+    )
+}
+
+#[query]
+fn get_difficulty_history(start: u64, length: u64) -> Vec<DifficultyPoint> {
+    difficulty_history(start, std::cmp::min(length, MAX_PAGE_SIZE))
+}
+
+#[query]
+fn get_block_time_summary(start: u64, length: u64) -> BlockTimeSummary {
+    block_time_summary(start, std::cmp::min(length, MAX_PAGE_SIZE))
+}
+
+#[query]
+fn get_blocks_per_owner(start: u64, length: u64) -> Vec<(Principal, u64)> {
+    blocks_per_owner(start, std::cmp::min(length, MAX_PAGE_SIZE))
+}
+
+/// First block height created at or after `timestamp`, for turning time windows into height ranges.
+#[query]
+fn get_height_at(timestamp: u64) -> u64 {
+    height_at(timestamp)
+}
+
+/// Estimated hashes per second of every miner that submitted shares in the last hour.
+#[query]
+fn get_hashrates() -> Vec<(Principal, u64)> {
+    estimate_hashrates(&window_shares(), ic_cdk::api::time(), HASHRATE_WINDOW)
+        .into_iter()
+        .collect()
+}
+
+#[query]
+fn get_miner_hashrate(miner: Principal) -> u64 {
+    estimate_hashrates(&window_shares(), ic_cdk::api::time(), HASHRATE_WINDOW)
+        .get(&miner)
+        .cloned()
+        .unwrap_or(0)
+}
+
+#[query]
+fn get_network_hashrate() -> u64 {
+    estimate_hashrates(&window_shares(), ic_cdk::api::time(), HASHRATE_WINDOW)
+        .values()
+        .fold(0_u64, |total, rate| total.saturating_add(*rate))
+}
+
 #[query]
 fn get_leaderboard() -> Vec<LeaderBoardEntry> {
     use std::collections::BTreeSet;
@@ -815,29 +2504,14 @@ fn get_leaderboard() -> Vec<LeaderBoardEntry> {
         let mut result: BTreeSet<LeaderBoardEntry> = BTreeSet::default();
 
         for (miner, _block_count) in biggest {
-            let (owner, block_count, miner_count): (Principal, u64, usize) = match
-                s.miner_to_owner.get(&miner)
-            {
-                Some(owner) => {
-                    let block_count = s.principal_to_miner
-                        .get(&owner)
-                        .map(|miners| {
-                            miners
-                                .iter()
-                                .map(|miner| *s.miner_to_mined_block.get(miner).unwrap_or(&0))
-                                .sum::<u64>()
-                        })
-                        .unwrap_or(0);
-
-                    let miner_count = s.principal_to_miner
-                        .get(&owner)
-                        .map(|miners| miners.len())
-                        .unwrap_or(0);
-
-                    (owner.clone(), block_count, miner_count)
-                }
-                None => { (Principal::anonymous(), 0, 0) }
+            let Some(owner) = s.historical_owner(&miner) else {
+                continue;
             };
+            let block_count = s.owner_block_count(&owner);
+            let miner_count = s.principal_to_miner
+                .get(&owner)
+                .map(|miners| miners.len())
+                .unwrap_or(0);
             result.insert(LeaderBoardEntry {
                 owner,
                 miner_count,
@@ -848,8 +2522,3 @@ fn get_leaderboard() -> Vec<LeaderBoardEntry> {
         result.iter().cloned().collect()
     })
 }
-
-fn nat_to_u64(nat: candid::Nat) -> Result<u64, String> {
-    use num_traits::cast::ToPrimitive;
-    nat.0.to_u64().ok_or_else(|| "Failed to convert Nat to u64".to_string())
-}
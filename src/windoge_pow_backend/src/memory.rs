@@ -9,6 +9,10 @@ use serde::{ Deserialize, Serialize };
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::hash::Hasher;
+use crate::governance::{ AuditEntry, Role };
+use crate::pool::Share;
+use crate::spawn::SpawnRecord;
+use crate::upgrade::WasmVersion;
 use crate::State;
 
 pub type Hash = u128; // 128-bit hash
@@ -152,6 +156,33 @@ impl Block {
         self.hash = ((hash128_high as u128) << 64) | (hash64 as u128);
     }
 
+    /// Whether `other` is this block with only the nonce and hash filled in.
+    pub fn same_template(&self, other: &Block) -> bool {
+        self.header == other.header && self.transactions == other.transactions
+    }
+
+    /// Proof-of-work hash of the header with `nonce`, as computed by the miners.
+    pub fn pow_hash(header: &BlockHeader, nonce: u128) -> Hash {
+        let mut hasher = RapidHasher::new(0);
+        let mut data = Vec::new();
+        data.extend_from_slice(&header.version.to_le_bytes());
+        data.extend_from_slice(&header.prev_hash.to_le_bytes());
+        data.extend_from_slice(&header.merkle_root.to_le_bytes());
+        data.extend_from_slice(&header.timestamp.to_le_bytes());
+        data.extend_from_slice(&nonce.to_le_bytes());
+
+        hasher.write(&data);
+        let hash64 = hasher.finish();
+
+        let hash128_high = {
+            let mut hasher = RapidHasher::new(hash64);
+            hasher.write(&hash64.to_le_bytes());
+            hasher.finish()
+        };
+
+        ((hash128_high as u128) << 64) | (hash64 as u128)
+    }
+
     pub fn genesis() -> Self {
         Self {
             header: BlockHeader {
@@ -194,6 +225,13 @@ impl<T> Storable for Cbor<T> where T: serde::Serialize + serde::de::DeserializeO
     const BOUND: Bound = Bound::Unbounded;
 }
 
+#[derive(Serialize, Deserialize)]
+struct StoredWasm {
+    registered_at: u64,
+    #[serde(with = "serde_bytes")]
+    module: Vec<u8>,
+}
+
 const MINER_TO_OWNER_MEM_ID: MemoryId = MemoryId::new(0);
 const TX_LOG_INDX_MEM_ID: MemoryId = MemoryId::new(1);
 const TX_LOG_DATA_MEM_ID: MemoryId = MemoryId::new(2);
@@ -204,6 +242,13 @@ const STATS_INDX_MEM_ID: MemoryId = MemoryId::new(6);
 const STATS_DATA_MEM_ID: MemoryId = MemoryId::new(7);
 const STATE_INDX_MEM_ID: MemoryId = MemoryId::new(8);
 const STATE_DATA_MEM_ID: MemoryId = MemoryId::new(9);
+const DEPOSIT_TO_OWNER_MEM_ID: MemoryId = MemoryId::new(10);
+const SPAWNS_MEM_ID: MemoryId = MemoryId::new(11);
+const ROLES_MEM_ID: MemoryId = MemoryId::new(12);
+const AUDIT_INDX_MEM_ID: MemoryId = MemoryId::new(13);
+const AUDIT_DATA_MEM_ID: MemoryId = MemoryId::new(14);
+const SHARES_MEM_ID: MemoryId = MemoryId::new(15);
+const WASMS_MEM_ID: MemoryId = MemoryId::new(16);
 
 type VM = VirtualMemory<DefMem>;
 
@@ -218,6 +263,37 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(MINER_TO_OWNER_MEM_ID)))
     });
 
+    static DEPOSIT_TO_OWNER: RefCell<
+        StableBTreeMap<u64, (Principal, u64), VM>
+    > = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(DEPOSIT_TO_OWNER_MEM_ID)))
+    });
+
+    static SPAWNS: RefCell<StableBTreeMap<u64, Cbor<SpawnRecord>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(SPAWNS_MEM_ID)))
+    });
+
+    static ROLES: RefCell<StableBTreeMap<Principal, Cbor<Role>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ROLES_MEM_ID)))
+    });
+
+    static AUDIT_LOG: RefCell<StableLog<Cbor<AuditEntry>, VM, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableLog::init(
+                mm.borrow().get(AUDIT_INDX_MEM_ID),
+                mm.borrow().get(AUDIT_DATA_MEM_ID)
+            ).expect("failed to initialize the audit log")
+        )
+    });
+
+    static SHARES: RefCell<StableBTreeMap<u64, Cbor<Share>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(SHARES_MEM_ID)))
+    });
+
+    static WASMS: RefCell<StableBTreeMap<Vec<u8>, Cbor<StoredWasm>, VM>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(WASMS_MEM_ID)))
+    });
+
     static USER_TO_BALANCE: RefCell<StableBTreeMap<Principal, u64, VM>> = MEMORY_MANAGER.with(|mm| {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(USER_TO_BALANCE_MEM_ID)))
     });
@@ -296,6 +372,15 @@ pub fn get_block(index: u64) -> Option<Block> {
     )
 }
 
+/// Blocks at heights `start..start + length`, clamped to the chain.
+pub fn blocks_range(start: u64, length: u64) -> Vec<Block> {
+    CHAIN.with(|s| {
+        let log = s.borrow();
+        let end = std::cmp::min(start.saturating_add(length), log.len());
+        (start..end).filter_map(|i| log.get(i).map(|b| b.0)).collect()
+    })
+}
+
 pub fn insert_new_transaction(block: u64) -> Result<u64, WriteError> {
     TX_LOG.with(|s| s.borrow_mut().append(&Cbor(block)))
 }
@@ -327,6 +412,22 @@ pub fn insert_new_miner(miner: Principal, owner: Principal, block_index: u64) {
     MINER_TO_OWNER.with(|s| s.borrow_mut().insert(miner, (owner, block_index)));
 }
 
+/// Changes the owner of a registered miner, keeping its creation block index.
+pub fn set_miner_owner(miner: Principal, owner: Principal) {
+    MINER_TO_OWNER.with(|s| {
+        let mut map = s.borrow_mut();
+        if let Some((_, block_index)) = map.get(&miner) {
+            map.insert(miner, (owner, block_index));
+        }
+    });
+}
+
+pub fn remove_miner(miner: Principal) {
+    MINER_TO_OWNER.with(|s| {
+        s.borrow_mut().remove(&miner);
+    });
+}
+
 pub fn get_miner_owner(miner: Principal) -> Option<Principal> {
     MINER_TO_OWNER.with(|s|
         s
@@ -344,6 +445,139 @@ pub fn get_miner_to_owner_and_index() -> Vec<(Principal, (Principal, u64))> {
     MINER_TO_OWNER.with(|s| s.borrow().iter().collect())
 }
 
+pub fn insert_deposit(block_index: u64, owner: Principal, amount: u64) {
+    DEPOSIT_TO_OWNER.with(|s| s.borrow_mut().insert(block_index, (owner, amount)));
+}
+
+pub fn is_deposit_processed(block_index: u64) -> bool {
+    DEPOSIT_TO_OWNER.with(|s| s.borrow().contains_key(&block_index))
+}
+
+pub fn total_deposited() -> u64 {
+    DEPOSIT_TO_OWNER.with(|s|
+        s
+            .borrow()
+            .iter()
+            .fold(0_u64, |total, (_, (_, amount))| total.saturating_add(amount))
+    )
+}
+
+pub fn insert_spawn(record: SpawnRecord) {
+    SPAWNS.with(|s| s.borrow_mut().insert(record.block_index, Cbor(record)));
+}
+
+pub fn get_spawn(block_index: u64) -> Option<SpawnRecord> {
+    SPAWNS.with(|s|
+        s
+            .borrow()
+            .get(&block_index)
+            .map(|r| r.0)
+    )
+}
+
+pub fn pending_spawns() -> Vec<SpawnRecord> {
+    SPAWNS.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, r)| r.0)
+            .filter(|r| r.is_pending())
+            .collect()
+    )
+}
+
+pub fn spawns_of(owner: Principal) -> Vec<SpawnRecord> {
+    SPAWNS.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, r)| r.0)
+            .filter(|r| r.owner == owner)
+            .collect()
+    )
+}
+
+pub fn set_role(principal: Principal, role: Role) {
+    ROLES.with(|s| s.borrow_mut().insert(principal, Cbor(role)));
+}
+
+pub fn remove_role(principal: Principal) -> Option<Role> {
+    ROLES.with(|s|
+        s
+            .borrow_mut()
+            .remove(&principal)
+            .map(|r| r.0)
+    )
+}
+
+pub fn get_role(principal: Principal) -> Option<Role> {
+    ROLES.with(|s|
+        s
+            .borrow()
+            .get(&principal)
+            .map(|r| r.0)
+    )
+}
+
+pub fn all_roles() -> Vec<(Principal, Role)> {
+    ROLES.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(p, r)| (p, r.0))
+            .collect()
+    )
+}
+
+pub fn append_audit_entry(entry: AuditEntry) -> Result<u64, WriteError> {
+    AUDIT_LOG.with(|s| s.borrow_mut().append(&Cbor(entry)))
+}
+
+pub fn audit_entries(start: u64, length: u64) -> Vec<AuditEntry> {
+    AUDIT_LOG.with(|s| {
+        let log = s.borrow();
+        let end = std::cmp::min(start.saturating_add(length), log.len());
+        (start..end).filter_map(|i| log.get(i).map(|e| e.0)).collect()
+    })
+}
+
+pub fn audit_entry_count() -> u64 {
+    AUDIT_LOG.with(|s| s.borrow().len())
+}
+
+/// Appends a share and drops the oldest ones beyond `window`.
+pub fn insert_share(share: Share, window: u64) {
+    SHARES.with(|s| {
+        let mut shares = s.borrow_mut();
+        let next = shares
+            .last_key_value()
+            .map(|(k, _)| k + 1)
+            .unwrap_or(0);
+        shares.insert(next, Cbor(share));
+
+        while shares.len() > window {
+            match shares.first_key_value() {
+                Some((k, _)) => {
+                    shares.remove(&k);
+                }
+                None => {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+pub fn window_shares() -> Vec<Share> {
+    SHARES.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(_, share)| share.0)
+            .collect()
+    )
+}
+
 pub fn add_balance(user: Principal, amount: u64) {
     USER_TO_BALANCE.with(|s| {
         let new_balance = s.borrow().get(&user).unwrap_or(0) + amount;
@@ -358,6 +592,16 @@ pub fn sub_balance(user: Principal, amount: u64) {
     });
 }
 
+/// Sum of all user balances, i.e. the circulating supply.
+pub fn total_balance() -> u64 {
+    USER_TO_BALANCE.with(|s|
+        s
+            .borrow()
+            .iter()
+            .fold(0_u64, |total, (_, balance)| total.saturating_add(balance))
+    )
+}
+
 pub fn get_balance(user: Principal) -> u64 {
     USER_TO_BALANCE.with(|s| s.borrow().get(&user).unwrap_or(0))
 }
@@ -375,6 +619,15 @@ pub fn get_stat(index: u64) -> Option<Stats> {
     )
 }
 
+/// Stats at indexes `start..start + length`. Stats `i` belongs to the block at height `i + 1`.
+pub fn stats_range(start: u64, length: u64) -> Vec<Stats> {
+    STATS.with(|s| {
+        let log = s.borrow();
+        let end = std::cmp::min(start.saturating_add(length), log.len());
+        (start..end).filter_map(|i| log.get(i).map(|b| b.0)).collect()
+    })
+}
+
 pub fn all_stats() -> Vec<Stats> {
     STATS.with(|s|
         s
@@ -394,6 +647,43 @@ pub fn get_stats(index: u64) -> Option<Stats> {
     )
 }
 
+/// Keeps `module` under `hash`; a wasm registered before keeps its original timestamp.
+pub fn insert_wasm(hash: Vec<u8>, module: Vec<u8>, now: u64) {
+    WASMS.with(|s| {
+        let mut wasms = s.borrow_mut();
+        if !wasms.contains_key(&hash) {
+            wasms.insert(hash, Cbor(StoredWasm { registered_at: now, module }));
+        }
+    });
+}
+
+pub fn get_wasm(hash: &[u8]) -> Option<Vec<u8>> {
+    WASMS.with(|s|
+        s
+            .borrow()
+            .get(&hash.to_vec())
+            .map(|w| w.0.module)
+    )
+}
+
+pub fn has_wasm(hash: &[u8]) -> bool {
+    WASMS.with(|s| s.borrow().contains_key(&hash.to_vec()))
+}
+
+pub fn wasm_versions() -> Vec<WasmVersion> {
+    WASMS.with(|s|
+        s
+            .borrow()
+            .iter()
+            .map(|(hash, w)| WasmVersion {
+                hash,
+                size: w.0.module.len() as u64,
+                registered_at: w.0.registered_at,
+            })
+            .collect()
+    )
+}
+
 pub fn add_state(state: State) -> Result<u64, WriteError> {
     STATE.with(|s| s.borrow_mut().append(&Cbor(state)))
 }
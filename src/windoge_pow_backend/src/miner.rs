@@ -1,6 +1,12 @@
 use candid::{ CandidType, Principal };
 use ic_base_types::PrincipalId;
 use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::main::{
+    CanisterIdRecord as CanisterIdArg,
+    CanisterSettings,
+    CanisterStatusResponse,
+    UpdateSettingsArgument,
+};
 use ic_management_canister_types::{
     CanisterIdRecord,
     CanisterInstallMode,
@@ -10,6 +16,9 @@ use ic_management_canister_types::{
     LogVisibilityV2,
 };
 use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+use crate::health::CanisterRunStatus;
+use crate::payment::nat_to_u64;
 
 #[derive(Debug, Clone, PartialEq, Eq, CandidType)]
 pub struct CallError {
@@ -95,6 +104,27 @@ pub async fn install_code(
     Ok(())
 }
 
+/// Upgrades the miner in place, running its `pre_upgrade` and `post_upgrade` hooks.
+pub async fn upgrade_code(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>
+) -> Result<(), CallError> {
+    let install_code = InstallCodeArgs {
+        mode: CanisterInstallMode::Upgrade,
+        canister_id: PrincipalId::from(canister_id),
+        wasm_module,
+        arg,
+        compute_allocation: None,
+        memory_allocation: None,
+        sender_canister_version: None,
+    };
+
+    call("install_code", 0, &install_code).await?;
+
+    Ok(())
+}
+
 pub async fn reinstall_code(
     canister_id: Principal,
     wasm_module: Vec<u8>,
@@ -115,6 +145,66 @@ pub async fn reinstall_code(
     Ok(())
 }
 
+/// What an owner sees of their miner's canister.
+#[derive(Clone, CandidType, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MinerCanisterStatus {
+    pub status: CanisterRunStatus,
+    pub cycles: u64,
+    pub memory_size: u64,
+    /// Sha256 of the installed wasm, comparable with `MinerVersion::wasm_hash`.
+    pub module_hash: Option<Vec<u8>>,
+    pub idle_cycles_burned_per_day: u64,
+    pub freezing_threshold: u64,
+}
+
+impl From<CanisterStatusResponse> for MinerCanisterStatus {
+    fn from(response: CanisterStatusResponse) -> Self {
+        Self {
+            status: response.status.into(),
+            cycles: nat_to_u64(response.cycles).unwrap_or(u64::MAX),
+            memory_size: nat_to_u64(response.memory_size).unwrap_or(u64::MAX),
+            module_hash: response.module_hash,
+            idle_cycles_burned_per_day: nat_to_u64(response.idle_cycles_burned_per_day).unwrap_or(
+                u64::MAX
+            ),
+            freezing_threshold: nat_to_u64(response.settings.freezing_threshold).unwrap_or(
+                u64::MAX
+            ),
+        }
+    }
+}
+
+/// Status of a miner; the backend can query it as the miner's controller.
+pub async fn canister_status(canister_id: Principal) -> Result<CanisterStatusResponse, CallError> {
+    call("canister_status", 0, &CanisterIdArg { canister_id }).await
+}
+
+/// Applies the settings that are `Some` and leaves the others unchanged.
+pub async fn update_settings(
+    canister_id: Principal,
+    settings: CanisterSettings
+) -> Result<(), CallError> {
+    call("update_settings", 0, &UpdateSettingsArgument { canister_id, settings }).await
+}
+
+pub async fn stop_canister(canister_id: Principal) -> Result<(), CallError> {
+    call("stop_canister", 0, &CanisterIdArg { canister_id }).await
+}
+
+pub async fn start_canister(canister_id: Principal) -> Result<(), CallError> {
+    call("start_canister", 0, &CanisterIdArg { canister_id }).await
+}
+
+/// Deletes a stopped canister; its remaining cycles are lost.
+pub async fn delete_canister(canister_id: Principal) -> Result<(), CallError> {
+    call("delete_canister", 0, &CanisterIdArg { canister_id }).await
+}
+
+/// Moves `cycles` from the backend's balance to `canister_id`.
+pub async fn deposit_cycles(canister_id: Principal, cycles: u64) -> Result<(), CallError> {
+    call("deposit_cycles", cycles, &CanisterIdArg { canister_id }).await
+}
+
 pub async fn create_canister(cycles_for_canister_creation: u64) -> Result<Principal, CallError> {
     let create_args = CreateCanisterArgs {
         settings: Some(CanisterSettingsArgs {
@@ -0,0 +1,716 @@
+use candid::{ CandidType, Decode, Nat, Principal };
+use serde::Deserialize;
+use std::future::Future;
+use crate::memory::{ insert_deposit, is_deposit_processed };
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<serde_bytes::ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Burn {
+    pub from: Account,
+    pub memo: Option<serde_bytes::ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Mint1 {
+    pub to: Account,
+    pub memo: Option<serde_bytes::ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Transfer {
+    pub to: Account,
+    pub fee: Option<Nat>,
+    pub from: Account,
+    pub memo: Option<serde_bytes::ByteBuf>,
+    pub created_at_time: Option<u64>,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct Transaction1 {
+    pub burn: Option<Burn>,
+    pub kind: String,
+    pub mint: Option<Mint1>,
+    pub timestamp: u64,
+    pub index: Nat,
+    pub transfer: Option<Transfer>,
+}
+
+/// Generic ICRC-3 block value.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(serde_bytes::ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentKind {
+    Transfer,
+    Mint,
+    Burn,
+    Approve,
+}
+
+impl PaymentKind {
+    fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "transfer" | "xfer" | "1xfer" | "2xfer" => Ok(Self::Transfer),
+            "mint" | "1mint" => Ok(Self::Mint),
+            "burn" | "1burn" => Ok(Self::Burn),
+            "approve" | "2approve" => Ok(Self::Approve),
+            other => Err(format!("unknown transaction kind: {}", other)),
+        }
+    }
+}
+
+/// A ledger transaction normalised from either `get_transaction` or ICRC-3 `icrc3_get_blocks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PaymentProof {
+    pub block_index: u64,
+    pub kind: PaymentKind,
+    pub from: Option<Account>,
+    pub to: Option<Account>,
+    pub amount: u64,
+    pub memo: Option<Vec<u8>>,
+    pub timestamp: u64,
+}
+
+impl PaymentProof {
+    pub fn from_transaction(block_index: u64, tx: Transaction1) -> Result<Self, String> {
+        let kind = PaymentKind::parse(&tx.kind)?;
+        let (from, to, amount, memo) = match kind {
+            PaymentKind::Transfer => {
+                let transfer = tx.transfer.ok_or_else(|| "expected transfer".to_string())?;
+                (Some(transfer.from), Some(transfer.to), transfer.amount, transfer.memo)
+            }
+            PaymentKind::Mint => {
+                let mint = tx.mint.ok_or_else(|| "expected mint".to_string())?;
+                (None, Some(mint.to), mint.amount, mint.memo)
+            }
+            PaymentKind::Burn => {
+                let burn = tx.burn.ok_or_else(|| "expected burn".to_string())?;
+                (Some(burn.from), None, burn.amount, burn.memo)
+            }
+            PaymentKind::Approve => {
+                return Err("expected transfer".to_string());
+            }
+        };
+
+        Ok(Self {
+            block_index,
+            kind,
+            from,
+            to,
+            amount: nat_to_u64(amount)?,
+            memo: memo.map(|m| m.into_vec()),
+            timestamp: tx.timestamp,
+        })
+    }
+
+    pub fn from_icrc3_block(block_index: u64, block: &Value) -> Result<Self, String> {
+        let block = as_map(block)?;
+        let tx = as_map(field(block, "tx")?)?;
+
+        let kind = match (field(block, "btype"), field(tx, "op")) {
+            (Ok(btype), _) => PaymentKind::parse(as_text(btype)?)?,
+            (Err(_), Ok(op)) => PaymentKind::parse(as_text(op)?)?,
+            (Err(e), Err(_)) => {
+                return Err(e);
+            }
+        };
+
+        let from = field(tx, "from").ok().map(as_account).transpose()?;
+        let to = field(tx, "to").ok().map(as_account).transpose()?;
+        let amount = nat_to_u64(as_nat(field(tx, "amt")?)?.clone())?;
+        let memo = field(tx, "memo")
+            .ok()
+            .map(|m| as_blob(m).map(|b| b.to_vec()))
+            .transpose()?;
+        let timestamp = nat_to_u64(as_nat(field(block, "ts")?)?.clone())?;
+
+        Ok(Self {
+            block_index,
+            kind,
+            from,
+            to,
+            amount,
+            memo,
+            timestamp,
+        })
+    }
+}
+
+/// The conditions a ledger transaction has to satisfy to count as a payment.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PaymentRules {
+    pub kinds: Vec<PaymentKind>,
+    pub from: Option<Principal>,
+    pub to: Principal,
+    pub to_subaccount: Option<Vec<u8>>,
+    pub min_amount: u64,
+    pub memo: Option<Vec<u8>>,
+    pub max_age: Option<u64>,
+}
+
+impl PaymentRules {
+    /// A plain transfer of any amount to the default subaccount of `to`.
+    pub fn transfer_to(to: Principal) -> Self {
+        Self {
+            kinds: vec![PaymentKind::Transfer],
+            from: None,
+            to,
+            to_subaccount: None,
+            min_amount: 0,
+            memo: None,
+            max_age: None,
+        }
+    }
+
+    pub fn verify(&self, proof: &PaymentProof, now: u64) -> Result<(), String> {
+        if !self.kinds.contains(&proof.kind) {
+            return Err(format!("unexpected transaction kind: {:?}", proof.kind));
+        }
+
+        if let Some(expected_from) = self.from {
+            match &proof.from {
+                Some(from) if from.owner == expected_from => {}
+                _ => {
+                    return Err("transfer not from caller".to_string());
+                }
+            }
+        }
+
+        // Burns have no recipient: the tokens went to the minting account,
+        // which callers only accept on ledgers where `to` is the minter.
+        if let Some(to) = &proof.to {
+            if to.owner != self.to {
+                return Err("transfer not to BIL canister".to_string());
+            }
+            if
+                !same_subaccount(
+                    to.subaccount.as_ref().map(|s| s.as_slice()),
+                    self.to_subaccount.as_deref()
+                )
+            {
+                return Err("transfer not to expected subaccount".to_string());
+            }
+        }
+
+        if proof.amount < self.min_amount {
+            return Err("transfer amount too low".to_string());
+        }
+
+        if let Some(memo) = &self.memo {
+            if proof.memo.as_ref() != Some(memo) {
+                return Err("transfer memo mismatch".to_string());
+            }
+        }
+
+        if let Some(max_age) = self.max_age {
+            if now.saturating_sub(proof.timestamp) > max_age {
+                return Err("transfer too old".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedgerApi {
+    /// Legacy `get_transaction : (nat) -> (opt Transaction)`.
+    GetTransaction,
+    /// ICRC-3 `icrc3_get_blocks : (vec GetBlocksArgs) -> (GetBlocksResult)`.
+    Icrc3GetBlocks,
+}
+
+/// Source of ledger transactions; implemented by `IcLedger` and by mocks.
+pub trait Ledger {
+    fn get_payment(&self, block_index: u64) -> impl Future<Output = Result<PaymentProof, String>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcLedger {
+    pub canister_id: Principal,
+    pub api: LedgerApi,
+}
+
+impl IcLedger {
+    pub fn new(canister_id: Principal, api: LedgerApi) -> Self {
+        Self { canister_id, api }
+    }
+
+    async fn get_transaction(&self, block_index: u64) -> Result<PaymentProof, String> {
+        let res = ic_cdk::api::call
+            ::call_raw(
+                self.canister_id,
+                "get_transaction",
+                candid::encode_args((Nat::from(block_index),)).unwrap(),
+                0
+            ).await
+            .map_err(|(code, msg)|
+                format!("Error while calling ledger canister ({}): {:?}", code as i32, msg)
+            )?;
+
+        match Decode!(&res, Option<Transaction1>).map_err(|e| format!("{:?}", e))? {
+            Some(tx) => PaymentProof::from_transaction(block_index, tx),
+            None => Err("Block not found".to_string()),
+        }
+    }
+
+    async fn icrc3_get_blocks(&self, block_index: u64) -> Result<PaymentProof, String> {
+        let args = vec![GetBlocksArgs {
+            start: Nat::from(block_index),
+            length: Nat::from(1_u64),
+        }];
+        let res = ic_cdk::api::call
+            ::call_raw(
+                self.canister_id,
+                "icrc3_get_blocks",
+                candid::encode_args((args,)).unwrap(),
+                0
+            ).await
+            .map_err(|(code, msg)|
+                format!("Error while calling ledger canister ({}): {:?}", code as i32, msg)
+            )?;
+
+        let result = Decode!(&res, GetBlocksResult).map_err(|e| format!("{:?}", e))?;
+        match result.blocks.iter().find(|b| b.id == Nat::from(block_index)) {
+            Some(block) => PaymentProof::from_icrc3_block(block_index, &block.block),
+            None => Err("Block not found".to_string()),
+        }
+    }
+}
+
+impl Ledger for IcLedger {
+    async fn get_payment(&self, block_index: u64) -> Result<PaymentProof, String> {
+        match self.api {
+            LedgerApi::GetTransaction => self.get_transaction(block_index).await,
+            LedgerApi::Icrc3GetBlocks => self.icrc3_get_blocks(block_index).await,
+        }
+    }
+}
+
+/// Fetches `block_index` from `ledger` and checks it against `rules`.
+/// Deduplication of block indexes is left to the caller.
+pub async fn verify_payment<L: Ledger>(
+    ledger: &L,
+    block_index: u64,
+    rules: &PaymentRules,
+    now: u64
+) -> Result<PaymentProof, String> {
+    let proof = ledger.get_payment(block_index).await?;
+    rules.verify(&proof, now)?;
+    Ok(proof)
+}
+
+/// Verifies a deposit of `block_index` and records it for `owner`, returning
+/// the amount to credit. A block is claimed once, also when another call
+/// claims it while the ledger is being asked.
+pub async fn claim_deposit<L: Ledger>(
+    ledger: &L,
+    block_index: u64,
+    rules: &PaymentRules,
+    owner: Principal,
+    now: u64
+) -> Result<u64, String> {
+    if is_deposit_processed(block_index) {
+        return Err("transaction already processed".to_string());
+    }
+
+    let payment = verify_payment(ledger, block_index, rules, now).await?;
+    if payment.amount == 0 {
+        return Err("amount must be greater than 0".to_string());
+    }
+
+    if is_deposit_processed(block_index) {
+        return Err("transaction already processed".to_string());
+    }
+    insert_deposit(block_index, owner, payment.amount);
+
+    Ok(payment.amount)
+}
+
+pub fn nat_to_u64(nat: Nat) -> Result<u64, String> {
+    use num_traits::cast::ToPrimitive;
+    nat.0.to_u64().ok_or_else(|| "Failed to convert Nat to u64".to_string())
+}
+
+fn same_subaccount(a: Option<&[u8]>, b: Option<&[u8]>) -> bool {
+    const DEFAULT: [u8; 32] = [0; 32];
+    a.unwrap_or(&DEFAULT) == b.unwrap_or(&DEFAULT)
+}
+
+fn field<'a>(map: &'a [(String, Value)], key: &str) -> Result<&'a Value, String> {
+    map.iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v)
+        .ok_or_else(|| format!("missing block field: {}", key))
+}
+
+fn as_map(value: &Value) -> Result<&[(String, Value)], String> {
+    match value {
+        Value::Map(map) => Ok(map.as_slice()),
+        _ => Err("expected map".to_string()),
+    }
+}
+
+fn as_text(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::Text(text) => Ok(text.as_str()),
+        _ => Err("expected text".to_string()),
+    }
+}
+
+fn as_nat(value: &Value) -> Result<&Nat, String> {
+    match value {
+        Value::Nat(nat) => Ok(nat),
+        _ => Err("expected nat".to_string()),
+    }
+}
+
+fn as_blob(value: &Value) -> Result<&[u8], String> {
+    match value {
+        Value::Blob(blob) => Ok(blob.as_slice()),
+        _ => Err("expected blob".to_string()),
+    }
+}
+
+fn as_account(value: &Value) -> Result<Account, String> {
+    let parts = match value {
+        Value::Array(parts) => parts,
+        _ => {
+            return Err("expected account".to_string());
+        }
+    };
+    let owner = parts
+        .first()
+        .ok_or_else(|| "expected account owner".to_string())
+        .and_then(as_blob)
+        .and_then(|b| Principal::try_from_slice(b).map_err(|e| format!("{:?}", e)))?;
+    let subaccount = parts
+        .get(1)
+        .map(as_blob)
+        .transpose()?
+        .map(|b| serde_bytes::ByteBuf::from(b.to_vec()));
+
+    Ok(Account { owner, subaccount })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::task::{ Context, Poll, Waker };
+
+    const NOW: u64 = 1_000_000;
+
+    struct MockLedger(BTreeMap<u64, PaymentProof>);
+
+    impl Ledger for MockLedger {
+        async fn get_payment(&self, block_index: u64) -> Result<PaymentProof, String> {
+            self.0.get(&block_index).cloned().ok_or_else(|| "Block not found".to_string())
+        }
+    }
+
+    /// The mock never suspends, one poll runs a future to completion.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("mock ledger future suspended"),
+        }
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn account(id: u8) -> Account {
+        Account { owner: principal(id), subaccount: None }
+    }
+
+    fn transfer_proof(block_index: u64, amount: u64, timestamp: u64) -> PaymentProof {
+        PaymentProof {
+            block_index,
+            kind: PaymentKind::Transfer,
+            from: Some(account(1)),
+            to: Some(account(2)),
+            amount,
+            memo: None,
+            timestamp,
+        }
+    }
+
+    fn rules() -> PaymentRules {
+        PaymentRules {
+            from: Some(principal(1)),
+            min_amount: 100,
+            max_age: Some(1_000),
+            ..PaymentRules::transfer_to(principal(2))
+        }
+    }
+
+    fn ledger() -> MockLedger {
+        MockLedger(
+            BTreeMap::from([
+                (1, transfer_proof(1, 100, NOW)),
+                (2, transfer_proof(2, 99, NOW)),
+                (3, transfer_proof(3, 100, NOW - 1_001)),
+                (
+                    4,
+                    PaymentProof {
+                        to: Some(account(3)),
+                        ..transfer_proof(4, 100, NOW)
+                    },
+                ),
+            ])
+        )
+    }
+
+    #[test]
+    fn accepts_matching_payment() {
+        let proof = block_on(verify_payment(&ledger(), 1, &rules(), NOW)).unwrap();
+        assert_eq!(proof, transfer_proof(1, 100, NOW));
+    }
+
+    #[test]
+    fn rejects_wrong_recipient() {
+        let res = block_on(verify_payment(&ledger(), 4, &rules(), NOW));
+        assert_eq!(res, Err("transfer not to BIL canister".to_string()));
+    }
+
+    #[test]
+    fn rejects_wrong_subaccount() {
+        let rules = PaymentRules { to_subaccount: Some(vec![1; 32]), ..rules() };
+        let res = block_on(verify_payment(&ledger(), 1, &rules, NOW));
+        assert_eq!(res, Err("transfer not to expected subaccount".to_string()));
+    }
+
+    #[test]
+    fn rejects_short_amount() {
+        let res = block_on(verify_payment(&ledger(), 2, &rules(), NOW));
+        assert_eq!(res, Err("transfer amount too low".to_string()));
+    }
+
+    #[test]
+    fn rejects_old_payment() {
+        let res = block_on(verify_payment(&ledger(), 3, &rules(), NOW));
+        assert_eq!(res, Err("transfer too old".to_string()));
+    }
+
+    #[test]
+    fn rejects_other_sender() {
+        let rules = PaymentRules { from: Some(principal(9)), ..rules() };
+        let res = block_on(verify_payment(&ledger(), 1, &rules, NOW));
+        assert_eq!(res, Err("transfer not from caller".to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_block() {
+        let res = block_on(verify_payment(&ledger(), 42, &rules(), NOW));
+        assert_eq!(res, Err("Block not found".to_string()));
+    }
+
+    #[test]
+    fn reused_deposit_is_rejected() {
+        let res = block_on(claim_deposit(&ledger(), 1, &rules(), principal(1), NOW));
+        assert_eq!(res, Ok(100));
+        assert!(is_deposit_processed(1));
+
+        let res = block_on(claim_deposit(&ledger(), 1, &rules(), principal(1), NOW));
+        assert_eq!(res, Err("transaction already processed".to_string()));
+    }
+
+    /// Claims the block for someone else while "awaiting" the ledger.
+    struct RacingLedger(MockLedger);
+
+    impl Ledger for RacingLedger {
+        async fn get_payment(&self, block_index: u64) -> Result<PaymentProof, String> {
+            insert_deposit(block_index, principal(9), 1);
+            self.0.get_payment(block_index).await
+        }
+    }
+
+    #[test]
+    fn deposit_claimed_during_verification_is_rejected() {
+        let ledger = RacingLedger(ledger());
+        let res = block_on(claim_deposit(&ledger, 1, &rules(), principal(1), NOW));
+        assert_eq!(res, Err("transaction already processed".to_string()));
+    }
+
+    #[test]
+    fn failed_claim_leaves_deposit_unprocessed() {
+        let res = block_on(claim_deposit(&ledger(), 2, &rules(), principal(1), NOW));
+        assert!(res.is_err());
+        assert!(!is_deposit_processed(2));
+    }
+
+    #[test]
+    fn from_transaction_reads_transfer() {
+        let tx = Transaction1 {
+            burn: None,
+            kind: "transfer".to_string(),
+            mint: None,
+            timestamp: NOW,
+            index: Nat::from(7_u64),
+            transfer: Some(Transfer {
+                to: account(2),
+                fee: None,
+                from: account(1),
+                memo: Some(serde_bytes::ByteBuf::from(vec![1, 2, 3])),
+                created_at_time: None,
+                amount: Nat::from(100_u64),
+            }),
+        };
+        let proof = PaymentProof::from_transaction(7, tx).unwrap();
+        assert_eq!(proof, PaymentProof {
+            memo: Some(vec![1, 2, 3]),
+            ..transfer_proof(7, 100, NOW)
+        });
+    }
+
+    #[test]
+    fn from_transaction_reads_burn() {
+        let tx = Transaction1 {
+            burn: Some(Burn {
+                from: account(1),
+                memo: None,
+                created_at_time: None,
+                amount: Nat::from(5_u64),
+            }),
+            kind: "burn".to_string(),
+            mint: None,
+            timestamp: NOW,
+            index: Nat::from(8_u64),
+            transfer: None,
+        };
+        let proof = PaymentProof::from_transaction(8, tx).unwrap();
+        assert_eq!(proof.kind, PaymentKind::Burn);
+        assert_eq!(proof.from, Some(account(1)));
+        assert_eq!(proof.to, None);
+        assert_eq!(proof.amount, 5);
+    }
+
+    #[test]
+    fn from_transaction_rejects_approve_and_missing_body() {
+        let approve = Transaction1 {
+            burn: None,
+            kind: "approve".to_string(),
+            mint: None,
+            timestamp: NOW,
+            index: Nat::from(9_u64),
+            transfer: None,
+        };
+        assert!(PaymentProof::from_transaction(9, approve).is_err());
+
+        let empty = Transaction1 {
+            burn: None,
+            kind: "transfer".to_string(),
+            mint: None,
+            timestamp: NOW,
+            index: Nat::from(9_u64),
+            transfer: None,
+        };
+        assert_eq!(
+            PaymentProof::from_transaction(9, empty),
+            Err("expected transfer".to_string())
+        );
+    }
+
+    fn icrc3_account(id: u8) -> Value {
+        Value::Array(vec![Value::Blob(serde_bytes::ByteBuf::from(principal(id).as_slice().to_vec()))])
+    }
+
+    fn icrc3_block(kind: (&str, &str), tx: Vec<(String, Value)>) -> Value {
+        let mut block = vec![
+            ("ts".to_string(), Value::Nat(Nat::from(NOW))),
+            ("tx".to_string(), Value::Map(tx)),
+        ];
+        block.push((kind.0.to_string(), Value::Text(kind.1.to_string())));
+        Value::Map(block)
+    }
+
+    #[test]
+    fn from_icrc3_block_reads_typed_transfer() {
+        let block = icrc3_block(("btype", "1xfer"), vec![
+            ("from".to_string(), icrc3_account(1)),
+            ("to".to_string(), icrc3_account(2)),
+            ("amt".to_string(), Value::Nat(Nat::from(100_u64))),
+        ]);
+        let proof = PaymentProof::from_icrc3_block(3, &block).unwrap();
+        assert_eq!(proof, transfer_proof(3, 100, NOW));
+    }
+
+    #[test]
+    fn from_icrc3_block_reads_op_and_memo() {
+        let tx = vec![
+            ("op".to_string(), Value::Text("xfer".to_string())),
+            ("from".to_string(), icrc3_account(1)),
+            ("to".to_string(), icrc3_account(2)),
+            ("amt".to_string(), Value::Nat(Nat::from(100_u64))),
+            ("memo".to_string(), Value::Blob(serde_bytes::ByteBuf::from(vec![9]))),
+        ];
+        let block = Value::Map(
+            vec![
+                ("ts".to_string(), Value::Nat(Nat::from(NOW))),
+                ("tx".to_string(), Value::Map(tx))
+            ]
+        );
+        let proof = PaymentProof::from_icrc3_block(3, &block).unwrap();
+        assert_eq!(proof, PaymentProof { memo: Some(vec![9]), ..transfer_proof(3, 100, NOW) });
+    }
+
+    #[test]
+    fn from_icrc3_block_rejects_malformed_blocks() {
+        let no_amount = icrc3_block(("btype", "1xfer"), vec![("to".to_string(), icrc3_account(2))]);
+        assert_eq!(
+            PaymentProof::from_icrc3_block(3, &no_amount),
+            Err("missing block field: amt".to_string())
+        );
+
+        let no_kind = Value::Map(
+            vec![
+                ("ts".to_string(), Value::Nat(Nat::from(NOW))),
+                ("tx".to_string(), Value::Map(vec![]))
+            ]
+        );
+        assert!(PaymentProof::from_icrc3_block(3, &no_kind).is_err());
+
+        assert_eq!(
+            PaymentProof::from_icrc3_block(3, &Value::Text("block".to_string())),
+            Err("expected map".to_string())
+        );
+    }
+}
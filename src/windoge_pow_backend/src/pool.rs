@@ -0,0 +1,88 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use crate::SEC_NANOS;
+
+/// Period over which hashrate is estimated.
+pub const HASHRATE_WINDOW: u64 = 60 * 60 * SEC_NANOS; // 1 hour
+/// Most shares a miner may submit in one call, i.e. from one mining step.
+pub const MAX_SHARES_PER_SUBMIT: usize = 32;
+
+/// A partial solution: a hash of the current block meeting the share
+/// difficulty but not necessarily the block difficulty.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Share {
+    pub miner: Principal,
+    pub block_height: u64,
+    pub difficulty: u32,
+    pub timestamp: u64,
+}
+
+impl Share {
+    /// Expected number of hashes needed to find this share.
+    pub fn work(&self) -> u128 {
+        1_u128 << std::cmp::min(self.difficulty, 64)
+    }
+}
+
+/// Splits `reward` over `shares` (owner, work) proportionally to their work
+/// (PPLNS over whatever window the caller passes in). Rounding dust and the
+/// whole reward when there is no work go to `finder`.
+pub fn split_reward(
+    reward: u64,
+    finder: Principal,
+    shares: &[(Principal, u128)]
+) -> Vec<(Principal, u64)> {
+    let mut work_per_owner: BTreeMap<Principal, u128> = BTreeMap::new();
+    for (owner, work) in shares {
+        *work_per_owner.entry(*owner).or_insert(0) += *work;
+    }
+
+    let total_work: u128 = work_per_owner.values().sum();
+    if total_work == 0 {
+        return vec![(finder, reward)];
+    }
+
+    let mut payouts: BTreeMap<Principal, u64> = work_per_owner
+        .into_iter()
+        .map(|(owner, work)| (owner, (((reward as u128) * work) / total_work) as u64))
+        .collect();
+
+    let paid: u64 = payouts.values().sum();
+    *payouts.entry(finder).or_insert(0) += reward - paid;
+
+    payouts
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .collect()
+}
+
+/// Hashes per second of each miner, estimated from the work of its shares newer
+/// than `now - window`. Shares are a sample of all hashes tried, so the estimate
+/// is only as precise as the share count allows.
+pub fn estimate_hashrates(shares: &[Share], now: u64, window: u64) -> BTreeMap<Principal, u64> {
+    let since = now.saturating_sub(window);
+    let recent: Vec<&Share> = shares
+        .iter()
+        .filter(|share| share.timestamp >= since)
+        .collect();
+
+    // Retained shares may cover less than the whole window.
+    let oldest = match recent.iter().map(|share| share.timestamp).min() {
+        Some(oldest) => oldest,
+        None => {
+            return BTreeMap::new();
+        }
+    };
+    let elapsed_secs = std::cmp::max((now - oldest) / SEC_NANOS, 1) as u128;
+
+    let mut work_per_miner: BTreeMap<Principal, u128> = BTreeMap::new();
+    for share in recent {
+        *work_per_miner.entry(share.miner).or_insert(0) += share.work();
+    }
+
+    work_per_miner
+        .into_iter()
+        .map(|(miner, work)| (miner, std::cmp::min(work / elapsed_secs, u64::MAX as u128) as u64))
+        .collect()
+}
@@ -0,0 +1,111 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// Failed attempts after which a spawn is abandoned and its payment refunded.
+pub const MAX_SPAWN_ATTEMPTS: u32 = 3;
+
+/// Steps of the miner spawn saga, in the order they are executed.
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SpawnStep {
+    PaymentVerified,
+    CanisterCreated,
+    CodeInstalled,
+    Registered,
+    /// The share of the payment `spawn_burn_percent` asks for is burned.
+    Burned,
+    Completed,
+    Failed,
+    Refunded,
+}
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum PaymentSource {
+    Transfer,
+    Approval,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SpawnRecord {
+    pub block_index: u64,
+    pub owner: Principal,
+    pub amount: u64,
+    pub source: PaymentSource,
+    pub step: SpawnStep,
+    pub canister_id: Option<Principal>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl SpawnRecord {
+    pub fn new(
+        block_index: u64,
+        owner: Principal,
+        amount: u64,
+        source: PaymentSource,
+        now: u64
+    ) -> Self {
+        Self {
+            block_index,
+            owner,
+            amount,
+            source,
+            step: SpawnStep::PaymentVerified,
+            canister_id: None,
+            attempts: 0,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !matches!(self.step, SpawnStep::Completed | SpawnStep::Refunded)
+    }
+
+    pub fn advance(&mut self, step: SpawnStep, now: u64) {
+        self.step = step;
+        self.last_error = None;
+        self.updated_at = now;
+    }
+
+    /// Records a failed attempt; once `MAX_SPAWN_ATTEMPTS` is reached the spawn is
+    /// moved to `Failed` so the next run compensates instead of retrying.
+    pub fn fail(&mut self, error: String, now: u64) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        self.updated_at = now;
+        if self.attempts >= MAX_SPAWN_ATTEMPTS && self.step < SpawnStep::Registered {
+            self.step = SpawnStep::Failed;
+        }
+    }
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<u64>> = RefCell::default();
+}
+
+/// Prevents a user call and the recovery timer from driving the same spawn concurrently.
+pub struct SpawnGuard(u64);
+
+impl SpawnGuard {
+    pub fn new(block_index: u64) -> Result<Self, String> {
+        IN_FLIGHT.with(|s| {
+            if !s.borrow_mut().insert(block_index) {
+                return Err("spawn already in progress".to_string());
+            }
+            Ok(Self(block_index))
+        })
+    }
+}
+
+impl Drop for SpawnGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|s| {
+            s.borrow_mut().remove(&self.0);
+        });
+    }
+}
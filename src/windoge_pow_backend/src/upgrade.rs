@@ -0,0 +1,294 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::cell::RefCell;
+use crate::State;
+
+/// Miners upgraded per batch when the admin does not pick a size.
+pub const UPGRADE_BATCH_SIZE: u64 = 10;
+
+/// Sha256 of a wasm module, the same hash the management canister reports as `module_hash`.
+pub fn wasm_hash(wasm: &[u8]) -> Vec<u8> {
+    Sha256::digest(wasm).to_vec()
+}
+
+pub fn hash_to_hex(hash: &[u8]) -> String {
+    hash.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A miner wasm the backend has embedded at some point, kept so fleets can roll back to it.
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct WasmVersion {
+    pub hash: Vec<u8>,
+    pub size: u64,
+    pub registered_at: u64,
+}
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum UpgradeStatus {
+    Installed,
+    Pending,
+    Upgraded,
+    RolledBack,
+    Failed,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MinerVersion {
+    /// Hash of the installed wasm; `None` for miners spawned before versions were
+    /// tracked, until their first upgrade reads it from the management canister.
+    pub wasm_hash: Option<Vec<u8>>,
+    /// Wasm the last upgrade replaced, what a rollback reinstalls.
+    pub previous_hash: Option<Vec<u8>>,
+    pub status: UpgradeStatus,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+impl MinerVersion {
+    pub fn installed(wasm_hash: Vec<u8>, now: u64) -> Self {
+        Self {
+            wasm_hash: Some(wasm_hash),
+            previous_hash: None,
+            status: UpgradeStatus::Installed,
+            last_error: None,
+            updated_at: now,
+        }
+    }
+
+    pub fn unknown(now: u64) -> Self {
+        Self {
+            wasm_hash: None,
+            previous_hash: None,
+            status: UpgradeStatus::Installed,
+            last_error: None,
+            updated_at: now,
+        }
+    }
+
+    pub fn upgraded(&mut self, wasm_hash: Vec<u8>, now: u64) {
+        if self.wasm_hash.as_ref() != Some(&wasm_hash) {
+            self.previous_hash = self.wasm_hash.replace(wasm_hash);
+        }
+        self.status = UpgradeStatus::Upgraded;
+        self.last_error = None;
+        self.updated_at = now;
+    }
+
+    pub fn rolled_back(&mut self, now: u64) {
+        if let Some(previous) = self.previous_hash.take() {
+            self.wasm_hash = Some(previous);
+        }
+        self.status = UpgradeStatus::RolledBack;
+        self.last_error = None;
+        self.updated_at = now;
+    }
+
+    pub fn fail(&mut self, error: String, now: u64) {
+        self.status = UpgradeStatus::Failed;
+        self.last_error = Some(error);
+        self.updated_at = now;
+    }
+}
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum FleetUpgradeKind {
+    Upgrade,
+    Rollback,
+}
+
+#[derive(Clone, Copy, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum FleetUpgradeState {
+    Running,
+    Stopped,
+    Completed,
+}
+
+#[derive(Clone, CandidType, Ord, PartialOrd, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct FleetUpgrade {
+    pub kind: FleetUpgradeKind,
+    /// Wasm the fleet moves to. A rollback moves the miners running it back to
+    /// their `previous_hash` instead.
+    pub target: Vec<u8>,
+    pub batch_size: u64,
+    pub state: FleetUpgradeState,
+    /// Miners still to process, in order. A miner leaves the queue once its outcome
+    /// is recorded, so an interrupted run picks up where it stopped.
+    pub queue: Vec<Principal>,
+    pub total: u64,
+    pub upgraded: u64,
+    pub failed: u64,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl FleetUpgrade {
+    pub fn new(
+        kind: FleetUpgradeKind,
+        target: Vec<u8>,
+        batch_size: u64,
+        queue: Vec<Principal>,
+        now: u64
+    ) -> Self {
+        let state = if queue.is_empty() {
+            FleetUpgradeState::Completed
+        } else {
+            FleetUpgradeState::Running
+        };
+        Self {
+            kind,
+            target,
+            batch_size,
+            state,
+            total: queue.len() as u64,
+            queue,
+            upgraded: 0,
+            failed: 0,
+            started_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == FleetUpgradeState::Running
+    }
+
+    pub fn next_batch(&self) -> Vec<Principal> {
+        self.queue
+            .iter()
+            .take(self.batch_size as usize)
+            .cloned()
+            .collect()
+    }
+
+    pub fn record(&mut self, miner: Principal, success: bool, now: u64) {
+        self.queue.retain(|m| *m != miner);
+        if success {
+            self.upgraded += 1;
+        } else {
+            self.failed += 1;
+        }
+        self.updated_at = now;
+        self.complete_if_empty();
+    }
+
+    /// Takes a miner that left the registry out of the queue without counting it.
+    pub fn drop_miner(&mut self, miner: Principal, now: u64) {
+        self.queue.retain(|m| *m != miner);
+        self.total = self.total.saturating_sub(1);
+        self.updated_at = now;
+        self.complete_if_empty();
+    }
+
+    /// A running upgrade with nothing left to process is done.
+    pub fn complete_if_empty(&mut self) {
+        if self.queue.is_empty() && self.is_running() {
+            self.state = FleetUpgradeState::Completed;
+        }
+    }
+}
+
+/// Registered miners not running `target`, including those with an unknown version.
+pub fn upgrade_queue(state: &State, target: &[u8]) -> Vec<Principal> {
+    state.miner_to_owner
+        .keys()
+        .filter(|miner| {
+            state.miner_versions
+                .get(miner)
+                .and_then(|v| v.wasm_hash.as_deref()) != Some(target)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Registered miners running `target` that remember what they ran before.
+pub fn rollback_queue(state: &State, target: &[u8]) -> Vec<Principal> {
+    state.miner_to_owner
+        .keys()
+        .filter(|miner| {
+            state.miner_versions.get(miner).is_some_and(|v| {
+                v.wasm_hash.as_deref() == Some(target) && v.previous_hash.is_some()
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+thread_local! {
+    static BATCH_IN_FLIGHT: RefCell<bool> = RefCell::default();
+}
+
+/// Keeps a resumed fleet upgrade from running a second batch loop next to the
+/// one still awaiting its last install.
+pub struct FleetUpgradeGuard;
+
+impl FleetUpgradeGuard {
+    pub fn new() -> Result<Self, String> {
+        BATCH_IN_FLIGHT.with(|s| {
+            if s.replace(true) {
+                return Err("fleet upgrade batch already in progress".to_string());
+            }
+            Ok(Self)
+        })
+    }
+}
+
+impl Drop for FleetUpgradeGuard {
+    fn drop(&mut self) {
+        BATCH_IN_FLIGHT.with(|s| {
+            s.replace(false);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn miner(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn empty_queue_is_completed() {
+        let upgrade = FleetUpgrade::new(FleetUpgradeKind::Upgrade, vec![1], 10, vec![], 5);
+        assert_eq!(upgrade.state, FleetUpgradeState::Completed);
+        assert!(!upgrade.is_running());
+        assert!(upgrade.next_batch().is_empty());
+    }
+
+    #[test]
+    fn queue_completes_once_processed() {
+        let mut upgrade = FleetUpgrade::new(
+            FleetUpgradeKind::Rollback,
+            vec![1],
+            1,
+            vec![miner(1), miner(2)],
+            5
+        );
+        assert!(upgrade.is_running());
+        assert_eq!(upgrade.next_batch(), vec![miner(1)]);
+
+        upgrade.record(miner(1), true, 6);
+        assert!(upgrade.is_running());
+        upgrade.drop_miner(miner(2), 7);
+        assert_eq!(upgrade.state, FleetUpgradeState::Completed);
+        assert_eq!((upgrade.total, upgrade.upgraded, upgrade.failed), (1, 1, 0));
+    }
+
+    #[test]
+    fn stopped_upgrade_stays_stopped_when_emptied() {
+        let mut upgrade = FleetUpgrade::new(
+            FleetUpgradeKind::Upgrade,
+            vec![1],
+            1,
+            vec![miner(1)],
+            5
+        );
+        upgrade.state = FleetUpgradeState::Stopped;
+        upgrade.drop_miner(miner(1), 6);
+        assert_eq!(upgrade.state, FleetUpgradeState::Stopped);
+    }
+}